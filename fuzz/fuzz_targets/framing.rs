@@ -0,0 +1,110 @@
+/*
+ * pipe-poll
+ * Copyright (C) 2021 Safin Singh
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Feeds arbitrary input through framing and decoding the way the epoll
+//! thread does, in reads of varying size. Run with
+//! `cargo fuzz run framing` from the repository root.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pipe_poll::{Encoding, Framing};
+
+fuzz_target!(|data: &[u8]| {
+	let (config, stream) = match data {
+		[a, b, c, d, stream @ ..] => ([*a, *b, *c, *d], stream),
+		_ => return,
+	};
+
+	let framing = match config[0] % 4 {
+		0 => Framing::Session,
+		1 => Framing::Delimiter(config[1]),
+		2 => Framing::LengthPrefixed {
+			big_endian: config[0] & 4 != 0,
+			width: 1 << (config[1] % 4),
+		},
+		_ => Framing::PerRead,
+	};
+	let encoding = match config[0] >> 4 & 3 {
+		0 => Encoding::Utf8,
+		1 => Encoding::Utf16Le,
+		2 => Encoding::Utf16Be,
+		_ => Encoding::Latin1,
+	};
+	let read_size = config[2] as usize + 1;
+	let max_bytes = config[3] as usize;
+
+	let mut buf = Vec::new();
+	let mut frames = Vec::new();
+	for read in stream.chunks(read_size) {
+		buf.extend_from_slice(read);
+		frames.extend(framing.split(&mut buf, false));
+	}
+	frames.extend(framing.split(&mut buf, true));
+
+	// Frames come out the same however the stream was cut into reads, except
+	// that cutting it is the whole point of `PerRead`.
+	let mut whole = stream.to_vec();
+	if framing != Framing::PerRead {
+		assert_eq!(frames, framing.split(&mut whole, true));
+		assert_eq!(buf, whole);
+	}
+
+	// With a limit what's held on to stays bounded however long a frame
+	// claims to be, and nothing changes until the limit is hit.
+	let mut buf = Vec::new();
+	let mut discarding = false;
+	let mut limited = Vec::new();
+	let mut cut = false;
+	for read in stream.chunks(read_size) {
+		buf.extend_from_slice(read);
+		let (complete, oversized) = framing.split_limited(
+			&mut buf,
+			&mut discarding,
+			Some(max_bytes),
+			false,
+		);
+		limited.extend(complete);
+		assert!(buf.len() <= max_bytes + framing.prefix_len());
+		if let Some(partial) = oversized {
+			assert!(partial.len() > max_bytes);
+			assert!(discarding);
+			let end = encoding.truncation_point(&partial, max_bytes);
+			assert!(end <= max_bytes);
+			let _ = encoding.decode(partial[..end].to_vec());
+			cut = true;
+		}
+	}
+	let (complete, oversized) =
+		framing.split_limited(&mut buf, &mut discarding, Some(max_bytes), true);
+	limited.extend(complete);
+	assert!(oversized.is_none() && !discarding);
+	if !cut {
+		assert_eq!(limited, frames);
+	}
+
+	let max = read_size;
+	for frame in frames {
+		if frame.len() > max {
+			let end = encoding.truncation_point(&frame, max);
+			assert!(end <= max);
+			let _ = encoding.decode(frame[..end].to_vec());
+		}
+		let _ = encoding.decode(frame);
+	}
+});
@@ -0,0 +1,41 @@
+/*
+ * pipe-poll
+ * Copyright (C) 2021 Safin Singh
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! `pipe-poll` without the async runtime: waits for one message on any of
+//! the given pipes, prints it and exits.
+
+use pipe_poll::{ListenOptions, PipeWriteListen};
+use std::{env, process};
+
+fn main() {
+	let pipes: Vec<String> = env::args().skip(1).collect();
+	if pipes.is_empty() || pipes.iter().any(|arg| arg.starts_with('-')) {
+		eprintln!("USAGE:\n\tpipe-poll-minimal <pipe>...");
+		process::exit(2);
+	}
+
+	let written = PipeWriteListen::new(&pipes, &ListenOptions::default())
+		.and_then(PipeWriteListen::wait);
+	match written {
+		Ok(written) => println!("{}", written),
+		Err(e) => {
+			eprintln!("Error: {}: {}", pipes.join(", "), e);
+			process::exit(1);
+		}
+	}
+}
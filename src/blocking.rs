@@ -0,0 +1,49 @@
+/*
+ * pipe-poll
+ * Copyright (C) 2021 Safin Singh
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Waiting on the crate's futures without an async runtime.
+
+use std::{
+	future::Future,
+	pin::Pin,
+	sync::Arc,
+	task::{Context, Poll, Wake, Waker},
+	thread::{self, Thread},
+};
+
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+	fn wake(self: Arc<Self>) {
+		self.0.unpark()
+	}
+}
+
+/// Polls `future` on the calling thread, parking it in between.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+	let mut future = Box::pin(future);
+	let waker = Waker::from(Arc::new(Unpark(thread::current())));
+	let mut cx = Context::from_waker(&waker);
+
+	loop {
+		if let Poll::Ready(output) = Pin::as_mut(&mut future).poll(&mut cx) {
+			return output;
+		}
+		thread::park();
+	}
+}
@@ -0,0 +1,182 @@
+/*
+ * pipe-poll
+ * Copyright (C) 2021 Safin Singh
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! `--control`: a Unix socket answering admin queries with JSON.
+//!
+//! A client connects, optionally sends a command line, and reads back one
+//! line of JSON before the server hangs up. Commands are `status` (the
+//! default when nothing is sent), describing every pipe, and `errors`, the
+//! log of recent non-fatal errors.
+
+use pipe_poll::PipeWatcher;
+use std::{
+	fmt::Write as _,
+	fs,
+	io::{self, BufRead, BufReader, Write},
+	os::unix::{
+		fs::FileTypeExt,
+		net::{UnixListener, UnixStream},
+	},
+	path::{Path, PathBuf},
+	sync::Arc,
+	thread,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How long a client gets to send its command before `status` is assumed.
+const COMMAND_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Removes the socket again when dropped.
+pub struct Control {
+	path: PathBuf,
+}
+
+impl Drop for Control {
+	fn drop(&mut self) {
+		let _ = fs::remove_file(&self.path);
+	}
+}
+
+/// Binds the control socket at `path`, replacing a stale one, and answers
+/// queries about `watcher` on a background thread.
+pub fn serve(path: &Path, watcher: Arc<PipeWatcher>) -> io::Result<Control> {
+	if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+		fs::remove_file(path)?;
+	}
+	let listener = UnixListener::bind(path)?;
+
+	thread::spawn(move || {
+		for stream in listener.incoming().flatten() {
+			// A client going away mid-answer is its own problem.
+			let _ = answer(stream, &watcher);
+		}
+	});
+
+	Ok(Control {
+		path: path.to_path_buf(),
+	})
+}
+
+fn answer(stream: UnixStream, watcher: &PipeWatcher) -> io::Result<()> {
+	stream.set_read_timeout(Some(COMMAND_TIMEOUT))?;
+
+	let mut command = String::new();
+	match BufReader::new(&stream).read_line(&mut command) {
+		Err(e)
+			if e.kind() == io::ErrorKind::WouldBlock
+				|| e.kind() == io::ErrorKind::TimedOut => {}
+		res => {
+			res?;
+		}
+	}
+
+	let reply = match command.trim() {
+		"" | "status" => status(watcher),
+		"errors" => errors(watcher),
+		other => format!(
+			r#"{{"error":{}}}"#,
+			json_string(&format!("unknown command `{}`", other))
+		),
+	};
+	writeln!(&stream, "{}", reply)
+}
+
+fn status(watcher: &PipeWatcher) -> String {
+	let mut json = String::from(r#"{"pipes":["#);
+
+	for (i, pipe) in watcher.pipe_stats().iter().enumerate() {
+		if i > 0 {
+			json.push(',');
+		}
+
+		let last_message =
+			pipe.last_message.map_or("null".to_string(), json_time);
+		let _ = write!(
+			json,
+			r#"{{"path":{},"messages":{},"bytes":{},"writer_connected":{},"last_message":{}}}"#,
+			json_string(&pipe.path.to_string_lossy()),
+			pipe.messages,
+			pipe.bytes,
+			pipe.writer_connected,
+			last_message,
+		);
+	}
+
+	json.push_str("]}");
+	json
+}
+
+fn errors(watcher: &PipeWatcher) -> String {
+	let errors = watcher
+		.recent_errors()
+		.iter()
+		.map(|e| {
+			format!(
+				r#"{{"at":{},"message":{}}}"#,
+				json_time(e.at),
+				json_string(&e.message)
+			)
+		})
+		.collect::<Vec<_>>();
+
+	format!(r#"{{"errors":[{}]}}"#, errors.join(","))
+}
+
+/// Seconds since the epoch, with milliseconds.
+fn json_time(t: SystemTime) -> String {
+	let t = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+	format!("{}.{:03}", t.as_secs(), t.subsec_millis())
+}
+
+pub fn json_string(s: &str) -> String {
+	let mut out = String::with_capacity(s.len() + 2);
+	out.push('"');
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if (c as u32) < 0x20 => {
+				let _ = write!(out, "\\u{:04x}", c as u32);
+			}
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn json_string_escapes() {
+		assert_eq!(json_string(""), r#""""#);
+		assert_eq!(json_string("plain"), r#""plain""#);
+		assert_eq!(json_string(r#"say "hi" \o/"#), r#""say \"hi\" \\o/""#);
+		assert_eq!(json_string("a\nb\r\tc"), r#""a\nb\r\tc""#);
+		assert_eq!(json_string("\0\x1b\x7f"), "\"\\u0000\\u001b\x7f\"");
+		assert_eq!(
+			json_string("caf\u{e9} \u{1f600}"),
+			"\"caf\u{e9} \u{1f600}\""
+		);
+	}
+}
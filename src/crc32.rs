@@ -0,0 +1,63 @@
+/*
+ * pipe-poll
+ * Copyright (C) 2021 Safin Singh
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! CRC-32 as used by zlib, gzip and PNG (reflected, polynomial 0xedb88320).
+
+const TABLE: [u32; 256] = table();
+
+const fn table() -> [u32; 256] {
+	let mut table = [0; 256];
+	let mut i = 0;
+	while i < 256 {
+		let mut crc = i as u32;
+		let mut bit = 0;
+		while bit < 8 {
+			crc = if crc & 1 != 0 {
+				crc >> 1 ^ 0xedb8_8320
+			} else {
+				crc >> 1
+			};
+			bit += 1;
+		}
+		table[i] = crc;
+		i += 1;
+	}
+	table
+}
+
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+	!bytes.iter().fold(!0, |crc, &b| {
+		TABLE[((crc ^ b as u32) & 0xff) as usize] ^ crc >> 8
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn known_vectors() {
+		assert_eq!(crc32(b""), 0);
+		assert_eq!(crc32(b"a"), 0xe8b7_be43);
+		assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+		assert_eq!(
+			crc32(b"The quick brown fox jumps over the lazy dog"),
+			0x414f_a339
+		);
+	}
+}
@@ -0,0 +1,128 @@
+/*
+ * pipe-poll
+ * Copyright (C) 2021 Safin Singh
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use libc::{
+	close, dup2, fork, getpid, open, pipe2, setsid, O_CLOEXEC, O_RDWR,
+	STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO,
+};
+use std::{
+	env,
+	fs::{self, File},
+	io::{self, Read, Write},
+	os::unix::io::FromRawFd,
+	path::{Path, PathBuf},
+	process,
+};
+
+/// The daemon's side of `daemonize()`. The original process waits until
+/// `ready()` or `fail()` is called, or this is dropped, and exits with the
+/// outcome, so startup errors still reach the terminal it was run from.
+/// Dropping this also removes the pidfile.
+pub struct Daemon {
+	/// Write end of the pipe the original process is reading.
+	status: Option<File>,
+	pidfile: Option<PathBuf>,
+}
+
+impl Daemon {
+	/// Startup is done; the original process exits successfully.
+	pub fn ready(&mut self) {
+		if let Some(mut status) = self.status.take() {
+			let _ = status.write_all(&[0]);
+		}
+	}
+
+	/// Startup failed; the original process prints `error` and exits with
+	/// status 1.
+	pub fn fail(&mut self, error: &str) {
+		if let Some(mut status) = self.status.take() {
+			let _ = status.write_all(error.as_bytes());
+		}
+	}
+}
+
+impl Drop for Daemon {
+	fn drop(&mut self) {
+		if let Some(path) = &self.pidfile {
+			let _ = fs::remove_file(path);
+		}
+	}
+}
+
+/// Detaches the calling process from its controlling terminal using the
+/// usual double fork: the first child calls `setsid` to lead a new session,
+/// and the second child (which, not being a session leader, can never
+/// reacquire a terminal) carries on as the daemon while the first child
+/// exits. The daemon changes into `/`, writes its PID to `pidfile` if given
+/// and points its standard streams at `/dev/null`. The original process
+/// stays until the daemon reports how its startup went, see `Daemon`.
+///
+/// Only the daemon returns. Call this before any other threads are started
+/// since `fork` doesn't carry them over.
+pub fn daemonize(pidfile: Option<&Path>) -> io::Result<Daemon> {
+	let mut fds = [0; 2];
+	check_errno!(pipe2(fds.as_mut_ptr(), O_CLOEXEC))?;
+	let (mut rx, tx) =
+		unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+	if check_errno!(fork())? != 0 {
+		drop(tx);
+		let mut status = Vec::new();
+		let _ = rx.read_to_end(&mut status);
+		match status.as_slice() {
+			[0] => process::exit(0),
+			[] => eprintln!("Error: the daemon exited during startup"),
+			error => eprintln!("Error: {}", String::from_utf8_lossy(error)),
+		}
+		process::exit(1);
+	}
+	drop(rx);
+	check_errno!(setsid())?;
+	if check_errno!(fork())? != 0 {
+		process::exit(0);
+	}
+
+	let mut daemon = Daemon {
+		status: Some(tx),
+		pidfile: None,
+	};
+	if let Err(e) = detach(pidfile) {
+		daemon.fail(&format!("Couldn't daemonize: {}", e));
+		process::exit(1);
+	}
+	daemon.pidfile = pidfile.map(Path::to_path_buf);
+
+	Ok(daemon)
+}
+
+fn detach(pidfile: Option<&Path>) -> io::Result<()> {
+	env::set_current_dir("/")?;
+	if let Some(path) = pidfile {
+		fs::write(path, format!("{}\n", unsafe { getpid() }))?;
+	}
+
+	let null = check_errno!(open(b"/dev/null\0".as_ptr().cast(), O_RDWR))?;
+	for fd in [STDIN_FILENO, STDOUT_FILENO, STDERR_FILENO].iter() {
+		check_errno!(dup2(null, *fd))?;
+	}
+	if null > STDERR_FILENO {
+		unsafe { close(null) };
+	}
+
+	Ok(())
+}
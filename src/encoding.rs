@@ -0,0 +1,173 @@
+/*
+ * pipe-poll
+ * Copyright (C) 2021 Safin Singh
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::io;
+
+/// Text encoding of what's written to the pipes. Messages are always handed
+/// out as UTF-8; a byte order mark at the start of a message is dropped.
+/// Framing happens on the raw bytes, so the UTF-16 encodings can't be used
+/// with `Framing::Delimiter` or `Framing::PerRead`, which could split a
+/// code unit.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Encoding {
+	#[default]
+	Utf8,
+	Utf16Le,
+	Utf16Be,
+	/// ISO-8859-1, where every byte is the code point of the same value.
+	Latin1,
+}
+
+impl Encoding {
+	/// Whether a character takes more than one byte, so byte framing
+	/// can't be used.
+	pub(crate) fn is_wide(self) -> bool {
+		matches!(self, Encoding::Utf16Le | Encoding::Utf16Be)
+	}
+
+	/// Turns one message into UTF-8, failing with `InvalidData`.
+	pub fn decode(self, mut bytes: Vec<u8>) -> io::Result<String> {
+		let bom: &[u8] = match self {
+			Encoding::Utf8 => b"\xef\xbb\xbf",
+			Encoding::Utf16Le => b"\xff\xfe",
+			Encoding::Utf16Be => b"\xfe\xff",
+			Encoding::Latin1 => b"",
+		};
+		if !bom.is_empty() && bytes.starts_with(bom) {
+			bytes.drain(..bom.len());
+		}
+
+		let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+		match self {
+			Encoding::Utf8 => {
+				String::from_utf8(bytes).map_err(|e| invalid(e.to_string()))
+			}
+			Encoding::Utf16Le | Encoding::Utf16Be => {
+				if bytes.len() % 2 != 0 {
+					return Err(invalid(
+						"odd number of bytes in UTF-16".into(),
+					));
+				}
+
+				let units = bytes.chunks_exact(2).map(|unit| {
+					let unit = [unit[0], unit[1]];
+					match self {
+						Encoding::Utf16Le => u16::from_le_bytes(unit),
+						_ => u16::from_be_bytes(unit),
+					}
+				});
+				char::decode_utf16(units)
+					.collect::<Result<String, _>>()
+					.map_err(|e| invalid(e.to_string()))
+			}
+			Encoding::Latin1 => Ok(bytes.into_iter().map(char::from).collect()),
+		}
+	}
+
+	/// Largest length of at most `max` that `bytes` can be cut to without
+	/// splitting a character. `bytes` has to be longer than `max`.
+	pub fn truncation_point(self, bytes: &[u8], max: usize) -> usize {
+		match self {
+			Encoding::Utf8 => {
+				let mut end = max;
+				while end > 0 && bytes[end] & 0xc0 == 0x80 {
+					end -= 1;
+				}
+				end
+			}
+			Encoding::Utf16Le | Encoding::Utf16Be => {
+				let end = max & !1;
+				// Don't separate a high surrogate from its low half.
+				let high = match self {
+					Encoding::Utf16Le if end >= 2 => bytes[end - 1],
+					Encoding::Utf16Be if end >= 2 => bytes[end - 2],
+					_ => return end,
+				};
+				if high & 0xfc == 0xd8 {
+					end - 2
+				} else {
+					end
+				}
+			}
+			Encoding::Latin1 => max,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn decode_drops_byte_order_mark() {
+		let decode = |enc: Encoding, bytes: &[u8]| enc.decode(bytes.to_vec());
+		assert_eq!(decode(Encoding::Utf8, b"\xef\xbb\xbfhi").unwrap(), "hi");
+		assert_eq!(decode(Encoding::Utf16Le, b"\xff\xfeh\0i\0").unwrap(), "hi");
+		assert_eq!(decode(Encoding::Utf16Be, b"\xfe\xff\0h\0i").unwrap(), "hi");
+		// Only at the start.
+		assert_eq!(
+			decode(Encoding::Utf16Le, b"h\0\xff\xfe").unwrap(),
+			"h\u{feff}"
+		);
+	}
+
+	#[test]
+	fn decode_utf16_surrogate_pairs() {
+		let smile = Encoding::Utf16Le.decode(b"=\xd8\x00\xde".to_vec());
+		assert_eq!(smile.unwrap(), "\u{1f600}");
+		let smile = Encoding::Utf16Be.decode(b"\xd8=\xde\x00".to_vec());
+		assert_eq!(smile.unwrap(), "\u{1f600}");
+	}
+
+	#[test]
+	fn decode_latin1_maps_every_byte() {
+		let text = Encoding::Latin1.decode(b"caf\xe9 \xff".to_vec());
+		assert_eq!(text.unwrap(), "caf\u{e9} \u{ff}");
+	}
+
+	#[test]
+	fn decode_rejects_invalid_input() {
+		let invalid = [
+			(Encoding::Utf8, &b"\xc3("[..]),
+			(Encoding::Utf16Le, b"h\0i"),
+			// A lone high surrogate.
+			(Encoding::Utf16Le, b"=\xd8a\0"),
+		];
+		for (enc, bytes) in invalid.iter() {
+			let err = enc.decode(bytes.to_vec()).unwrap_err();
+			assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+		}
+	}
+
+	#[test]
+	fn truncation_keeps_characters_whole() {
+		let utf8 = "h\u{e9}llo".as_bytes();
+		assert_eq!(Encoding::Utf8.truncation_point(utf8, 2), 1);
+		assert_eq!(Encoding::Utf8.truncation_point(utf8, 3), 3);
+
+		let le = b"a\0=\xd8\x00\xde";
+		assert_eq!(Encoding::Utf16Le.truncation_point(le, 3), 2);
+		assert_eq!(Encoding::Utf16Le.truncation_point(le, 4), 2);
+		assert_eq!(Encoding::Utf16Le.truncation_point(le, 5), 2);
+		let be = b"\0a\xd8=\xde\x00";
+		assert_eq!(Encoding::Utf16Be.truncation_point(be, 4), 2);
+		assert_eq!(Encoding::Utf16Be.truncation_point(b"\0a\0b\0c", 5), 4);
+
+		assert_eq!(Encoding::Latin1.truncation_point(b"abc", 2), 2);
+	}
+}
@@ -0,0 +1,108 @@
+/*
+ * pipe-poll
+ * Copyright (C) 2021 Safin Singh
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{error::Error, fmt, io};
+
+/// Everything that can go wrong while watching a pipe.
+#[derive(Debug)]
+pub enum PipeError {
+	/// The path exists but isn't a named pipe.
+	NotAFifo,
+	/// The `ListenOptions` can't be used together, or with these paths.
+	InvalidOptions(String),
+	OpenFailed(io::Error),
+	/// Creating the epoll instance, registering a pipe with it or waiting
+	/// on it failed.
+	EpollFailed(io::Error),
+	ReadFailed(io::Error),
+	WriteFailed(io::Error),
+	/// No writer connected in time.
+	Timeout,
+	/// The watcher has stopped and won't produce any more messages.
+	Cancelled,
+	/// The writer hung up without writing anything.
+	WriterClosed,
+	/// A message went over `ListenOptions::max_bytes`, which is included.
+	TooLarge(usize),
+	/// With `ListenOptions::verify_checksum`, a message didn't match the
+	/// CRC-32 it ended with. The message is dropped.
+	ChecksumMismatch {
+		expected: u32,
+		actual: u32,
+	},
+	/// A bounded subscription with `SlowSubscriber::Error` was full and
+	/// dropped this many of its oldest messages.
+	Missed(usize),
+	/// More than `ListenOptions::max_lag` bytes were left waiting in a pipe
+	/// after a read.
+	Lagging {
+		pending: usize,
+		max: usize,
+	},
+	/// The epoll thread panicked, so nothing more will be read.
+	Panicked,
+}
+
+impl fmt::Display for PipeError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			PipeError::NotAFifo => write!(f, "not a named pipe"),
+			PipeError::InvalidOptions(e) => write!(f, "invalid options: {}", e),
+			PipeError::OpenFailed(e) => write!(f, "failed to open pipe: {}", e),
+			PipeError::EpollFailed(e) => write!(f, "epoll failed: {}", e),
+			PipeError::ReadFailed(e) => write!(f, "failed to read pipe: {}", e),
+			PipeError::WriteFailed(e) => {
+				write!(f, "failed to write pipe: {}", e)
+			}
+			PipeError::Timeout => write!(f, "timed out waiting for a writer"),
+			PipeError::Cancelled => write!(f, "watcher stopped"),
+			PipeError::WriterClosed => {
+				write!(f, "writer closed the pipe without writing")
+			}
+			PipeError::TooLarge(max) => {
+				write!(f, "message longer than {} bytes", max)
+			}
+			PipeError::ChecksumMismatch { expected, actual } => write!(
+				f,
+				"checksum mismatch: expected {:08x}, got {:08x}",
+				expected, actual
+			),
+			PipeError::Missed(n) => {
+				write!(f, "subscriber fell behind, missed {} messages", n)
+			}
+			PipeError::Lagging { pending, max } => write!(
+				f,
+				"falling behind: {} bytes waiting in a pipe, over {}",
+				pending, max
+			),
+			PipeError::Panicked => write!(f, "the epoll thread panicked"),
+		}
+	}
+}
+
+impl Error for PipeError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			PipeError::OpenFailed(e)
+			| PipeError::EpollFailed(e)
+			| PipeError::ReadFailed(e)
+			| PipeError::WriteFailed(e) => Some(e),
+			_ => None,
+		}
+	}
+}
@@ -0,0 +1,273 @@
+/*
+ * pipe-poll
+ * Copyright (C) 2021 Safin Singh
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{convert::TryFrom, mem};
+
+/// How the bytes read from a pipe are cut into messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Framing {
+	/// Everything written until the last writer hangs up is one message,
+	/// with surrounding whitespace trimmed.
+	#[default]
+	Session,
+	/// Messages end with this byte, which isn't part of them. Anything left
+	/// over when the last writer hangs up is delivered as a final message.
+	Delimiter(u8),
+	/// Each message is preceded by its length in bytes, an unsigned integer
+	/// `width` bytes long, which is kept between 1 and 8.
+	LengthPrefixed { big_endian: bool, width: usize },
+	/// Whatever a single `read(2)` returns is a message, for producers that
+	/// send one message per `write(2)`. That only holds while the reader
+	/// keeps up: writes made between two reads come out as one message, and
+	/// a write over `PIPE_BUF` (4 KiB) or over the read size (64 KiB) may
+	/// be split across several.
+	PerRead,
+}
+
+impl Framing {
+	/// Takes every complete frame off the front of `buf`. At `eof` whatever
+	/// remains counts as a frame too, except with `LengthPrefixed` where it's
+	/// left in `buf` as the start of a frame that never got finished.
+	pub fn split(self, buf: &mut Vec<u8>, eof: bool) -> Vec<Vec<u8>> {
+		let mut frames = Vec::new();
+
+		if let Framing::LengthPrefixed { big_endian, width } = self {
+			let width = width.clamp(1, 8);
+			let mut start = 0;
+			while buf.len() - start >= width {
+				let prefix = &buf[start..start + width];
+				let len = if big_endian {
+					prefix.iter().fold(0, |len, &b| len << 8 | b as u64)
+				} else {
+					prefix.iter().rev().fold(0, |len, &b| len << 8 | b as u64)
+				};
+				let end = match usize::try_from(len)
+					.ok()
+					.and_then(|len| (start + width).checked_add(len))
+				{
+					Some(end) if end <= buf.len() => end,
+					_ => break,
+				};
+				frames.push(buf[start + width..end].to_vec());
+				start = end;
+			}
+			buf.drain(..start);
+			return frames;
+		}
+
+		if let Framing::Delimiter(delim) = self {
+			let mut start = 0;
+			while let Some(len) = buf[start..].iter().position(|&b| b == delim)
+			{
+				frames.push(buf[start..start + len].to_vec());
+				start += len + 1;
+			}
+			buf.drain(..start);
+		}
+
+		if (eof || self == Framing::PerRead) && !buf.is_empty() {
+			frames.push(mem::take(buf));
+		}
+
+		frames
+	}
+
+	/// `split()` for a pipe's buffered input, with `max` bytes (not counting
+	/// a length prefix) as the most of an unfinished frame to hold on to.
+	/// Past that the frame's start is taken off `buf`, without its prefix,
+	/// and returned alongside the complete frames, and `discarding` is set:
+	/// the rest of that frame is then dropped as it arrives, up to its
+	/// delimiter or, with no delimiter to resynchronise on, to the end of
+	/// the session. At `eof` nothing counts as oversized; what `split()`
+	/// leaves behind is still in `buf`.
+	pub fn split_limited(
+		self,
+		buf: &mut Vec<u8>,
+		discarding: &mut bool,
+		max: Option<usize>,
+		eof: bool,
+	) -> (Vec<Vec<u8>>, Option<Vec<u8>>) {
+		if *discarding {
+			let end = match self {
+				Framing::Delimiter(delim) => {
+					buf.iter().position(|&b| b == delim)
+				}
+				Framing::Session
+				| Framing::LengthPrefixed { .. }
+				| Framing::PerRead => None,
+			};
+			match end {
+				Some(end) => {
+					buf.drain(..=end);
+					*discarding = false;
+				}
+				None => buf.clear(),
+			}
+			*discarding &= !eof;
+		}
+
+		let frames = self.split(buf, eof);
+		let oversized = match max {
+			Some(max)
+				if !eof
+					&& buf.len().saturating_sub(self.prefix_len()) > max =>
+			{
+				let mut partial = mem::take(buf);
+				partial.drain(..self.prefix_len().min(partial.len()));
+				*discarding = true;
+				Some(partial)
+			}
+			_ => None,
+		};
+
+		(frames, oversized)
+	}
+
+	/// Bytes in front of each message which aren't part of it.
+	pub fn prefix_len(self) -> usize {
+		match self {
+			Framing::LengthPrefixed { width, .. } => width.clamp(1, 8),
+			_ => 0,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn session_waits_for_eof() {
+		let mut buf = b"hello ".to_vec();
+		assert!(Framing::Session.split(&mut buf, false).is_empty());
+
+		buf.extend_from_slice(b"world");
+		let frames = Framing::Session.split(&mut buf, true);
+		assert_eq!(frames, vec![b"hello world".to_vec()]);
+		assert!(buf.is_empty());
+	}
+
+	#[test]
+	fn delimiter_keeps_partial_frame() {
+		let mut buf = b"a\n\nbc\nd".to_vec();
+		let frames = Framing::Delimiter(b'\n').split(&mut buf, false);
+		assert_eq!(frames, vec![b"a".to_vec(), b"".to_vec(), b"bc".to_vec()]);
+		assert_eq!(buf, b"d");
+
+		let frames = Framing::Delimiter(b'\n').split(&mut buf, true);
+		assert_eq!(frames, vec![b"d".to_vec()]);
+		assert!(buf.is_empty());
+	}
+
+	#[test]
+	fn length_prefixed_both_byte_orders() {
+		let le = Framing::LengthPrefixed {
+			big_endian: false,
+			width: 2,
+		};
+		let mut buf = b"\x02\x00hi\x01\x00!\x05\x00abc".to_vec();
+		assert_eq!(
+			le.split(&mut buf, false),
+			vec![b"hi".to_vec(), b"!".to_vec()]
+		);
+		assert_eq!(buf, b"\x05\x00abc");
+
+		let be = Framing::LengthPrefixed {
+			big_endian: true,
+			width: 4,
+		};
+		let mut buf = b"\x00\x00\x00\x03abc".to_vec();
+		assert_eq!(be.split(&mut buf, false), vec![b"abc".to_vec()]);
+		assert!(buf.is_empty());
+	}
+
+	#[test]
+	fn length_prefixed_leaves_unfinished_frame_at_eof() {
+		let framing = Framing::LengthPrefixed {
+			big_endian: true,
+			width: 1,
+		};
+		let mut buf = b"\x04ab".to_vec();
+		assert!(framing.split(&mut buf, true).is_empty());
+		assert_eq!(buf, b"\x04ab");
+	}
+
+	#[test]
+	fn length_prefixed_huge_prefix_waits() {
+		let framing = Framing::LengthPrefixed {
+			big_endian: true,
+			width: 8,
+		};
+		let mut buf = vec![0xff; 12];
+		assert!(framing.split(&mut buf, false).is_empty());
+		assert_eq!(buf.len(), 12);
+	}
+
+	#[test]
+	fn per_read_takes_everything() {
+		let mut buf = b"one\ntwo".to_vec();
+		let frames = Framing::PerRead.split(&mut buf, false);
+		assert_eq!(frames, vec![b"one\ntwo".to_vec()]);
+		assert!(Framing::PerRead.split(&mut buf, true).is_empty());
+	}
+
+	#[test]
+	fn limited_discards_up_to_the_delimiter() {
+		let lines = Framing::Delimiter(b'\n');
+		let mut discarding = false;
+		let mut buf = b"ok\ntoo long".to_vec();
+		let (frames, oversized) =
+			lines.split_limited(&mut buf, &mut discarding, Some(4), false);
+		assert_eq!(frames, vec![b"ok".to_vec()]);
+		assert_eq!(oversized.as_deref(), Some(&b"too long"[..]));
+		assert!(discarding && buf.is_empty());
+
+		buf.extend_from_slice(b" still");
+		let (frames, oversized) =
+			lines.split_limited(&mut buf, &mut discarding, Some(4), false);
+		assert!(frames.is_empty() && oversized.is_none());
+		assert!(discarding && buf.is_empty());
+
+		buf.extend_from_slice(b"!\nnext\n");
+		let (frames, _) =
+			lines.split_limited(&mut buf, &mut discarding, Some(4), false);
+		assert_eq!(frames, vec![b"next".to_vec()]);
+		assert!(!discarding);
+	}
+
+	#[test]
+	fn limited_drops_a_huge_prefix_without_waiting() {
+		let framing = Framing::LengthPrefixed {
+			big_endian: true,
+			width: 4,
+		};
+		let mut discarding = false;
+		let mut buf = b"\xff\xff\xff\xffabcdef".to_vec();
+		let (frames, oversized) =
+			framing.split_limited(&mut buf, &mut discarding, Some(4), false);
+		assert!(frames.is_empty());
+		assert_eq!(oversized.as_deref(), Some(&b"abcdef"[..]));
+		assert!(discarding);
+
+		// Nothing to resynchronise on until the session ends.
+		buf.extend_from_slice(b"\x00\x00\x00\x01x");
+		let (frames, _) =
+			framing.split_limited(&mut buf, &mut discarding, Some(4), true);
+		assert!(frames.is_empty() && buf.is_empty() && !discarding);
+	}
+}
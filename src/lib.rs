@@ -0,0 +1,62 @@
+/*
+ * pipe-poll
+ * Copyright (C) 2021 Safin Singh
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Asynchronously poll named pipes (with `epoll(7)`) until they are
+//! available for `read(2)` operations.
+
+/// Runs a libc call, turning its -1 return into `errno` as an `io::Error`.
+macro_rules! check_errno {
+	($ex:expr) => {{
+		let _res = unsafe { $ex };
+		if _res == -1 {
+			Err(std::io::Error::last_os_error())
+		} else {
+			Ok(_res)
+		}
+	}};
+}
+
+mod blocking;
+mod crc32;
+#[cfg(feature = "daemon")]
+mod daemon;
+mod encoding;
+mod error;
+mod framing;
+mod listen;
+mod mmap;
+mod reader;
+mod request;
+mod secret;
+mod seqpacket;
+mod watcher;
+mod writers;
+
+#[cfg(feature = "daemon")]
+pub use daemon::{daemonize, Daemon};
+pub use encoding::Encoding;
+pub use error::PipeError;
+pub use framing::Framing;
+pub use listen::PipeWriteListen;
+pub use reader::{
+	allowed_cpus, Backend, Chunk, Lag, ListenOptions, LoggedError, MergeOrder,
+	Message, Oversize, PipeStats, SlowSubscriber, Stats,
+};
+pub use request::{request, write_to_pipe};
+pub use secret::Secret;
+pub use watcher::{Chunks, NextMessage, PipeWatcher, Subscription};
@@ -0,0 +1,75 @@
+/*
+ * pipe-poll
+ * Copyright (C) 2021 Safin Singh
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::blocking::block_on;
+use crate::reader::{self, Shared};
+use crate::{ListenOptions, PipeError, Stats};
+use std::{
+	future::Future,
+	path::Path,
+	pin::Pin,
+	sync::Arc,
+	task::{Context, Poll},
+};
+
+/// Resolves with the first message written to any of the watched pipes.
+pub struct PipeWriteListen {
+	shared: Arc<Shared>,
+}
+
+impl Future for PipeWriteListen {
+	type Output = Result<String, PipeError>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		reader::poll_message(&self.shared, reader::PRIMARY, cx)
+			.map_ok(|msg| msg.content)
+	}
+}
+
+impl PipeWriteListen {
+	/// Opens every pipe in `paths` and spawns the epoll thread, which stops
+	/// after the first message. A writer hanging up without writing resolves
+	/// the future with `PipeError::WriterClosed`.
+	pub fn new<P: AsRef<Path>>(
+		paths: &[P],
+		opts: &ListenOptions,
+	) -> Result<Self, PipeError> {
+		let shared = reader::spawn(paths, opts, false)?;
+		Ok(PipeWriteListen { shared })
+	}
+
+	/// Blocks the calling thread until the future resolves, for callers
+	/// without an async runtime.
+	pub fn wait(self) -> Result<String, PipeError> {
+		block_on(self)
+	}
+
+	/// Counters describing how the handoff from the epoll thread to this
+	/// future has been behaving.
+	pub fn stats(&self) -> Stats {
+		self.shared.stats()
+	}
+}
+
+/// Dropping the future before it resolves stops the epoll thread and closes
+/// the pipes.
+impl Drop for PipeWriteListen {
+	fn drop(&mut self) {
+		self.shared.shutdown();
+	}
+}
@@ -16,162 +16,1613 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use anyhow::{Context as _, Result};
-use async_std::task;
+mod control;
+mod output;
+mod rate;
+
+use anyhow::{bail, Context as _, Result};
+use async_std::{future, task};
+use control::Control;
 use libc::{
-	epoll_create1, epoll_ctl, epoll_event, epoll_wait, EPOLLIN, EPOLL_CTL_ADD,
-	O_CLOEXEC,
+	c_int, signal, EPOLLET, EPOLLEXCLUSIVE, EPOLLIN, EPOLLONESHOT, EPOLLPRI,
+	EPOLLRDHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2, SIG_ERR,
+};
+use output::{
+	Batch, Compressed, Compression, Exec, Format, Formatted, MessageSink,
+	OnSinkError, RawStdout, Received, Rotating, Syslog, Tee, WriteSink,
+};
+use pipe_poll::{
+	allowed_cpus, write_to_pipe, Backend, Encoding, Framing, Lag,
+	ListenOptions, MergeOrder, Oversize, PipeError, PipeWatcher,
+	PipeWriteListen, Secret,
 };
+use rate::RateMeter;
 use std::{
 	env,
-	fs::File,
-	future::Future,
-	io::Read,
-	os::unix::prelude::AsRawFd,
-	pin::Pin,
+	ffi::{CStr, CString},
+	fs::{self, OpenOptions},
+	io::{self, Read, Write},
+	mem::MaybeUninit,
+	os::unix::fs::FileTypeExt,
+	path::{Path, PathBuf},
 	process,
-	sync::{Arc, Mutex},
-	task::{Context, Poll, Waker},
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
 	thread,
-	time::Duration,
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-const MAX_EVENTS: i32 = 1;
 const HELP: &str = r#"
 USAGE:
-	pipe-poll <pipe>
+	pipe-poll [OPTIONS] <pipe>...
+	pipe-poll bench-latency [--iterations <n>] <req> <resp>
+	pipe-poll check-framing --framing <framing> < <sample>
 
 ARGUMENTS:
-	pipe - location of named pipe
+	pipe - location of named pipe, may be given more than once; a glob
+	       (quoted, e.g. '/run/app-*.pipe') watches every named pipe
+	       matching it at startup
+
+OPTIONS:
+	--follow              - keep reading and print every message as it
+	                        arrives instead of stopping after the first
+	--count-only          - keep reading until interrupted, then print
+	                        only the number of messages and bytes received
+	--max-messages <n>    - with --follow or --count-only, stop after <n>
+	                        messages
+	--output <path>       - append output to <path> instead of stdout, may
+	                        be given more than once to write to several
+	                        files; `-` is stdout
+	--syslog <f>.<l>      - send output to syslog with facility <f> (user,
+	                        daemon, local0 to local7, ...) and level <l>
+	                        (err, warning, notice, info, ...), e.g.
+	                        local0.info; may be combined with other
+	                        outputs
+	--output-dir <dir>    - write output to files in <dir> named after the
+	                        UTC time each was started, e.g.
+	                        20210704T120000Z.log, starting a new one every
+	                        --rotate-interval; may be given more than once
+	--rotate-interval <t> - how long each --output-dir file covers, in
+	                        seconds or with an s, m, h or d suffix
+	                        (default: 1h)
+	--sink <spec>         - an output with its own settings, may be given
+	                        more than once; <spec> is stdout, file=<path>,
+	                        dir=<path> or syslog[=<f>.<l>] (default
+	                        user.info) followed by any of
+	                          :plain      - as is (default)
+	                          :json       - one JSON object per line
+	                          :color      - with the time and source
+	                                        highlighted for a terminal
+	                          :template=<t>
+	                                      - like --template, taking up the
+	                                        rest of <spec>
+	                          :gzip :zstd - compressed, like --compress
+	                          :grep=<s>   - only messages containing <s>
+	                          :abort :continue
+	                                      - like --on-sink-error
+	                        e.g. --sink stdout:color --sink file=log:json
+	--template <t>        - write each message as <t> with {message},
+	                        {source}, {tag} (its --tag, or else {source})
+	                        and {checksum} (the CRC-32 of its bytes)
+	                        filled in; --sink formats override it
+	--tag <path>=<alias>  - show messages from <path> as coming from
+	                        <alias> instead, e.g. --tag /run/app-1.pipe=app1;
+	                        may be given more than once
+	--on-sink-error <p>   - what to do when writing to the preceding
+	                        --output, --output-dir or --sink fails, or to
+	                        every output when given before the first one
+	                          abort    - stop with an error (default)
+	                          continue - report it and keep writing to the
+	                                     other outputs
+	--mmap                - with --output, write each session's raw bytes
+	                        straight into <path> through a memory mapping
+	                        as they're read, for very large messages; this
+	                        needs a single pipe and session framing
+	--compress <codec>    - compress everything written into one gzip or
+	                        zstd stream, using the gzip or zstd binary
+	--ack-pipe <path>     - after each message has been written out, or
+	                        with --follow dropped for its size or a failed
+	                        --exec, write a line to the named pipe <path>
+	                        so the producer knows it was handled; this
+	                        waits for the producer to open <path>
+	--ack-token <line>    - what to write to --ack-pipe (default: ack)
+	--eof-marker <line>   - write <line> after the last message when
+	                        stopping normally (on SIGINT/SIGTERM, after
+	                        --max-messages or in one-shot mode after the
+	                        message), but not after an error
+	--exec <cmd>          - pipe every message through the shell command
+	                        <cmd>, printing its output instead
+	--exec-persistent     - with --exec, keep a single instance of <cmd>
+	                        running and exchange one line per message with
+	                        it instead of running it for every message
+	--echo                - with --follow, write every message back into
+	                        the pipe it came from after `echo: `, for
+	                        testing producers; messages starting with the
+	                        prefix are taken to be echoes and ignored
+	--echo-prefix <s>     - --echo with <s> instead of `echo: `
+	--cpu <n>             - pin the epoll thread to CPU core <n>
+	--min-writers <k>     - don't read anything until <k> writers have
+	                        opened the pipes or, with --seqpacket,
+	                        connected; FIFO writers are found through
+	                        /proc, where other users' processes are only
+	                        visible to root
+	--skip <n>            - discard the first <n> messages, e.g. a
+	                        handshake sent by the producer
+	--open-timeout <secs> - give up if no writer shows up within <secs>
+	--merge-order <order> - how to order pipes that become readable in the
+	                        same epoll wakeup (default: fd)
+	                          fd         - the order pipes were given in
+	                          bytes      - most bytes available first
+	                          roundrobin - start after the pipe served last
+	--events <flags>      - comma separated epoll flags to register each
+	                        pipe with (default: EPOLLIN), any of EPOLLIN,
+	                        EPOLLPRI, EPOLLRDHUP, EPOLLET, EPOLLONESHOT
+	                        and EPOLLEXCLUSIVE
+	--framing <framing>   - how to cut what's read into messages
+	                          session     - one per write session, i.e.
+	                                        until the last writer hangs
+	                                        up (default)
+	                          lines       - one per line
+	                          delimiter:c - ended by byte c, which may be
+	                                        \n, \t, \r, \0 or 0xNN
+	                          length-prefixed:<e>:<w>
+	                                      - preceded by their length as a
+	                                        be or le unsigned integer of
+	                                        width u8, u16, u32 or u64
+	--message-per-read    - instead of --framing, make whatever each read
+	                        returns a message, for producers writing one
+	                        message per write(2); writes made while
+	                        pipe-poll is busy get merged, and writes over
+	                        4 KiB may be split
+	--encoding <enc>      - text encoding of what's written to the pipes,
+	                        one of utf-8 (default), utf-16le, utf-16be and
+	                        latin1; a byte order mark is dropped. UTF-16
+	                        needs session or length-prefixed framing
+	--seqpacket           - listen on Unix SOCK_SEQPACKET sockets created at
+	                        each <pipe> path instead, every datagram being
+	                        a message of its own
+	--batch <n>           - with --follow, write messages out <n> at a time
+	                        as a JSON array of strings, acknowledging them
+	                        once they've been written
+	--batch-timeout <secs>
+	                      - write out a partial --batch once its first
+	                        message has waited for <secs>
+	--batch-separator <s> - join each --batch with <s> instead, which may
+	                        contain \n and \t
+	--min-size <n>        - with --follow, leave out messages shorter than
+	                        <n> bytes as framed, before decoding and
+	                        trimming, e.g. keepalives
+	--max-size <n>        - with --follow, leave out messages longer than
+	                        <n> bytes; unlike --max-bytes they're still
+	                        read in full and counted
+	--verify-checksum     - every message ends with a big-endian CRC-32 of
+	                        the rest, e.g. inside length-prefixed framing;
+	                        check and strip it, dropping messages that
+	                        don't match
+	--secret              - the messages are secrets, e.g. passphrases:
+	                        zero every buffer they pass through once done
+	                        with and write them out as they are, unbuffered,
+	                        to stdout or --output files only
+	--max-bytes <n>       - longest message accepted, in bytes
+	--on-oversize <what>  - what to do with a message over --max-bytes
+	                          error    - stop with an error (default)
+	                          truncate - keep only the first <n> bytes
+	                          skip     - drop it and carry on
+	--max-lag <n>         - most bytes left unread in a pipe after a read
+	                        before pipe-poll counts as falling behind
+	--on-lag <what>       - what to do when a pipe goes over --max-lag
+	                          warn  - print a warning each time it does
+	                                  (default)
+	                          abort - stop with an error
+	--drain-on-start      - read everything already buffered in a pipe on
+	                        its first wakeup before the steady state loop
+	--latest-only         - when several messages from a pipe are waiting
+	                        to be written out, drop all but the newest
+	--latest-interval <secs>
+	                      - with --follow and --latest-only, write out at
+	                        most one message per <secs>, the newest
+	--debug-events        - with --follow, add the epoll events behind each
+	                        message to :json output, e.g.
+	                          "events":["EPOLLIN","EPOLLHUP"]
+	--show-rate           - with --follow or --count-only, print messages
+	                        and bytes read per second to stderr every
+	                        second
+	--rate-window <t>     - average --show-rate over the last <t>, e.g. 1m
+	                        (default 10s); implies --show-rate
+	--control <path>      - with --follow or --count-only, answer queries
+	                        on a Unix socket at <path>: `status` (default)
+	                        for per pipe counters or `errors` for recent
+	                        non-fatal errors, e.g.
+	                          echo errors | socat - UNIX-CONNECT:<path>
+	--daemonize           - detach and keep running in the background, with
+	                        --follow or --count-only and an output other
+	                        than stdout (needs the `daemon` feature)
+	--pidfile <path>      - with --daemonize, write the daemon's PID here,
+	                        removing it again on exit
+
+SIGNALS:
+	With --follow or --count-only, SIGINT and SIGTERM stop cleanly: with
+	--follow, everything already read, like a pending --batch or
+	--latest-only message, is written out before exiting. SIGUSR1 pauses
+	reading so writers block once the pipes fill up, and SIGUSR2 resumes
+	it.
+
+BENCH-LATENCY:
+	Writes a timestamp to <req> <n> times (default 1000), waits for it to
+	be echoed back on <resp> and reports round trip percentiles. Something
+	has to do the echoing, e.g.
+	  while :; do x=$(cat req); echo "$x" > resp; done
+	A plain `cat req > resp` loop reopens <resp> before the previous reply
+	has been read, which merges replies together.
+
+CHECK-FRAMING:
+	Cuts a captured sample read from stdin into frames the way --framing
+	would and prints how many there are and their sizes, to try out a
+	framing before pointing pipe-poll at the real producer.
 
 EXAMPLES:
 	mkfifo pipe
 	pipe-poll pipe
+	pipe-poll --count-only pipe
+	pipe-poll --follow --merge-order bytes a b
+	pipe-poll --follow --framing lines --drain-on-start pipe
+	pipe-poll --follow --exec 'jq -c --unbuffered .field' --exec-persistent pipe
+	pipe-poll --follow --sink stdout:color --sink file=a.ndjson:json a b
+	pipe-poll --follow --daemonize --pidfile /run/pp.pid --output pp.log pipe
+	pipe-poll bench-latency --iterations 10000 req resp
+	pipe-poll check-framing --framing length-prefixed:be:u32 < sample.bin
 "#;
 
-macro_rules! handle_errno {
-	($ex:expr) => {{
-		use std::io;
+/// `--sink syslog`'s priority.
+const DEFAULT_SYSLOG_PRIORITY: c_int = libc::LOG_USER | libc::LOG_INFO;
+
+/// How far back `--show-rate` looks.
+const DEFAULT_RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// What `--echo` marks its echoes with.
+const DEFAULT_ECHO_PREFIX: &str = "echo: ";
+
+/// How long an `--output-dir` file is written to by default.
+const DEFAULT_ROTATE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How often `SHUTDOWN` is checked while waiting for messages.
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_shutdown(_: c_int) {
+	SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+/// With `--daemonize`, the handshake telling the process that started the
+/// daemon whether its startup worked out, see `watch()`.
+#[cfg(feature = "daemon")]
+static DAEMON: std::sync::Mutex<Option<pipe_poll::Daemon>> =
+	std::sync::Mutex::new(None);
+
+/// Set by SIGUSR1 and cleared by SIGUSR2, see `apply_pause()`.
+static PAUSE: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_pause(sig: c_int) {
+	PAUSE.store(sig == SIGUSR1, Ordering::SeqCst);
+}
 
-		let _res = unsafe { $ex };
-		if _res == -1 {
+/// Routes SIGINT and SIGTERM to `SHUTDOWN` so the watcher can be shut down
+/// cleanly, and SIGUSR1 and SIGUSR2 to `PAUSE`.
+fn handle_signals() {
+	let handlers = [
+		(SIGINT, request_shutdown as extern "C" fn(c_int)),
+		(SIGTERM, request_shutdown),
+		(SIGUSR1, request_pause),
+		(SIGUSR2, request_pause),
+	];
+	for (sig, handler) in handlers.iter() {
+		if unsafe { signal(*sig, *handler as usize) } == SIG_ERR {
 			panic!("{:?}", io::Error::last_os_error())
-		} else {
-			_res
 		}
-	}};
+	}
+}
 
-	($($ex:expr),*) => {{
-		$(
-			handle_errno!($ex);
-		)*
-	}};
+/// Pauses or resumes `watcher` after SIGUSR1 or SIGUSR2.
+fn apply_pause(watcher: &PipeWatcher) {
+	let pause = PAUSE.load(Ordering::SeqCst);
+	if pause == watcher.is_paused() {
+		return;
+	}
+
+	if pause {
+		watcher.pause();
+		eprintln!("Paused, send SIGUSR2 to resume");
+	} else {
+		watcher.resume();
+		eprintln!("Resumed");
+	}
 }
 
-struct PipeWriteListenState {
-	waker: Option<Waker>,
-	file: File,
-	complete: bool,
-	content: Option<String>,
+/// An `--output`, `--output-dir` or `--sink`.
+struct Output {
+	target: Target,
+	on_error: OnSinkError,
+	/// Overrides `--template`.
+	format: Option<Format>,
+	/// Overrides `--compress`.
+	compress: Option<Compression>,
+	grep: Option<String>,
+}
+
+impl Output {
+	fn new(target: Target, on_error: OnSinkError) -> Self {
+		Output {
+			target,
+			on_error,
+			format: None,
+			compress: None,
+			grep: None,
+		}
+	}
 }
 
-struct PipeWriteListen {
-	state: Arc<Mutex<PipeWriteListenState>>,
+enum Target {
+	Stdout,
+	File(PathBuf),
+	/// Files rotated every `--rotate-interval`.
+	Dir(PathBuf),
+	/// The facility and level to log at.
+	Syslog(c_int),
+}
+
+#[derive(Default)]
+struct Cli {
+	follow: bool,
+	count_only: bool,
+	max_messages: Option<usize>,
+	opts: ListenOptions,
+	pipes: Vec<String>,
+	outputs: Vec<Output>,
+	rotate_interval: Option<Duration>,
+	latest_interval: Option<Duration>,
+	debug_events: bool,
+	/// `--show-rate`'s window.
+	show_rate: Option<Duration>,
+	min_size: Option<usize>,
+	max_size: Option<usize>,
+	batch: Option<usize>,
+	batch_timeout: Option<Duration>,
+	batch_separator: Option<String>,
+	template: Option<String>,
+	on_sink_error: OnSinkError,
+	mmap: bool,
+	control: Option<PathBuf>,
+	compress: Option<Compression>,
+	eof_marker: Option<String>,
+	ack_pipe: Option<PathBuf>,
+	ack_token: Option<String>,
+	exec: Option<String>,
+	exec_persistent: bool,
+	/// `--echo`'s prefix.
+	echo: Option<String>,
+	/// `--tag`s, as paths and their aliases.
+	tags: Vec<(PathBuf, String)>,
+	#[cfg(feature = "daemon")]
+	daemonize: bool,
+	#[cfg(feature = "daemon")]
+	pidfile: Option<PathBuf>,
+}
+
+fn parse_merge_order(s: &str) -> Result<MergeOrder> {
+	match s {
+		"fd" => Ok(MergeOrder::Fd),
+		"bytes" => Ok(MergeOrder::Bytes),
+		"roundrobin" => Ok(MergeOrder::RoundRobin),
+		_ => bail!(
+			"unknown merge order `{}`, expected fd, bytes or roundrobin",
+			s
+		),
+	}
+}
+
+fn parse_framing(s: &str) -> Result<Framing> {
+	if let Some(prefix) = s.strip_prefix("length-prefixed:") {
+		let (endian, width) = prefix.split_once(':').unwrap_or((prefix, ""));
+		let big_endian = match endian {
+			"be" => true,
+			"le" => false,
+			_ => bail!("unknown byte order `{}`, expected be or le", endian),
+		};
+		let width = match width {
+			"u8" => 1,
+			"u16" => 2,
+			"u32" => 4,
+			"u64" => 8,
+			_ => bail!(
+				"unknown length type `{}`, expected u8, u16, u32 or u64",
+				width
+			),
+		};
+		return Ok(Framing::LengthPrefixed { big_endian, width });
+	}
+
+	let delim = match s {
+		"session" => return Ok(Framing::Session),
+		"lines" => b'\n',
+		_ => match s.strip_prefix("delimiter:") {
+			Some("\\n") => b'\n',
+			Some("\\t") => b'\t',
+			Some("\\r") => b'\r',
+			Some("\\0") => b'\0',
+			Some(hex) if hex.starts_with("0x") => {
+				u8::from_str_radix(&hex[2..], 16).with_context(|| {
+					format!("invalid delimiter byte `{}`", hex)
+				})?
+			}
+			Some(c) if c.len() == 1 => c.as_bytes()[0],
+			_ => bail!(
+				"unknown framing `{}`, expected session, lines, delimiter:<c> \
+				 or length-prefixed:<e>:<w>",
+				s
+			),
+		},
+	};
+
+	Ok(Framing::Delimiter(delim))
 }
 
-impl Future for PipeWriteListen {
-	type Output = String;
+/// Parses e.g. `local0.warning` into a `syslog(3)` priority.
+fn parse_syslog_priority(s: &str) -> Result<c_int> {
+	let (facility, level) = s.split_once('.').unwrap_or((s, "info"));
+	let facility = match facility {
+		"user" => libc::LOG_USER,
+		"daemon" => libc::LOG_DAEMON,
+		"auth" => libc::LOG_AUTH,
+		"authpriv" => libc::LOG_AUTHPRIV,
+		"cron" => libc::LOG_CRON,
+		"ftp" => libc::LOG_FTP,
+		"lpr" => libc::LOG_LPR,
+		"mail" => libc::LOG_MAIL,
+		"news" => libc::LOG_NEWS,
+		"syslog" => libc::LOG_SYSLOG,
+		"uucp" => libc::LOG_UUCP,
+		"local0" => libc::LOG_LOCAL0,
+		"local1" => libc::LOG_LOCAL1,
+		"local2" => libc::LOG_LOCAL2,
+		"local3" => libc::LOG_LOCAL3,
+		"local4" => libc::LOG_LOCAL4,
+		"local5" => libc::LOG_LOCAL5,
+		"local6" => libc::LOG_LOCAL6,
+		"local7" => libc::LOG_LOCAL7,
+		_ => bail!("unknown syslog facility `{}`", facility),
+	};
+	let level = match level {
+		"emerg" => libc::LOG_EMERG,
+		"alert" => libc::LOG_ALERT,
+		"crit" => libc::LOG_CRIT,
+		"err" => libc::LOG_ERR,
+		"warning" => libc::LOG_WARNING,
+		"notice" => libc::LOG_NOTICE,
+		"info" => libc::LOG_INFO,
+		"debug" => libc::LOG_DEBUG,
+		_ => bail!("unknown syslog level `{}`", level),
+	};
 
-	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-		let mut state = self.state.lock().unwrap();
+	Ok(facility | level)
+}
 
-		if state.complete {
-			if let Some(ct) = state.content.take() {
-				Poll::Ready(ct)
-			} else {
-				unreachable!()
+/// Parses a `--sink` spec, `<target>[:<option>...]`.
+fn parse_sink(spec: &str, on_error: OnSinkError) -> Result<Output> {
+	let mut parts = spec.split(':');
+	let target = match parts.next().unwrap_or_default() {
+		"stdout" | "-" => Target::Stdout,
+		target => match target.split_once('=') {
+			Some(("file", path)) => Target::File(path.into()),
+			Some(("dir", path)) => Target::Dir(path.into()),
+			Some(("syslog", priority)) => {
+				Target::Syslog(parse_syslog_priority(priority)?)
 			}
-		} else {
-			state.waker = Some(cx.waker().clone());
-			Poll::Pending
+			_ if target == "syslog" => Target::Syslog(DEFAULT_SYSLOG_PRIORITY),
+			_ => bail!(
+				"unknown sink `{}`, expected stdout, file=<path>, dir=<path> \
+				 or syslog[=<facility>.<level>]",
+				target
+			),
+		},
+	};
+
+	let mut output = Output::new(target, on_error);
+	while let Some(option) = parts.next() {
+		if let Some(template) = option.strip_prefix("template=") {
+			// The rest of the spec, `:` included.
+			let rest: Vec<_> =
+				Some(template).into_iter().chain(parts).collect();
+			output.format = Some(Format::Template(rest.join(":")));
+			break;
 		}
+
+		match option {
+			"plain" => output.format = Some(Format::Plain),
+			"json" => output.format = Some(Format::Json),
+			"color" => output.format = Some(Format::Color),
+			"gzip" => output.compress = Some(Compression::Gzip),
+			"zstd" => output.compress = Some(Compression::Zstd),
+			"abort" => output.on_error = OnSinkError::Abort,
+			"continue" => output.on_error = OnSinkError::Continue,
+			_ => match option.strip_prefix("grep=") {
+				Some(text) => output.grep = Some(text.to_string()),
+				None => bail!("unknown option `{}` in --sink {}", option, spec),
+			},
+		}
+	}
+
+	Ok(output)
+}
+
+/// Parses e.g. `90`, `90s`, `30m`, `1h` or `1d`, plain numbers being
+/// seconds.
+fn parse_interval(s: &str) -> Result<Duration> {
+	let (n, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+		Some(i) => s.split_at(i),
+		None => (s, "s"),
+	};
+	let unit = match unit {
+		"s" => 1,
+		"m" => 60,
+		"h" => 60 * 60,
+		"d" => 24 * 60 * 60,
+		_ => bail!("unknown unit `{}` in `{}`, expected s, m, h or d", unit, s),
+	};
+	let n: u64 = n
+		.parse()
+		.with_context(|| format!("invalid interval `{}`", s))?;
+	if n == 0 {
+		bail!("the interval can't be zero");
 	}
+
+	Ok(Duration::from_secs(n * unit))
 }
 
-impl PipeWriteListen {
-	fn new(loc: &str) -> Self {
-		let state = Arc::new(Mutex::new(PipeWriteListenState {
-			waker: None,
-			complete: false,
-			file: File::open(loc).unwrap(),
-			content: None,
-		}));
+/// Parses a comma separated list of epoll flag names into a bitmask.
+/// `EPOLLOUT` is left out since the read end of a pipe is never writable,
+/// as are `EPOLLHUP` and `EPOLLERR` which epoll always reports.
+fn parse_events(s: &str) -> Result<u32> {
+	s.split(',').try_fold(0, |mask, name| {
+		let flag = match name.trim() {
+			"EPOLLIN" => EPOLLIN,
+			"EPOLLPRI" => EPOLLPRI,
+			"EPOLLRDHUP" => EPOLLRDHUP,
+			"EPOLLET" => EPOLLET,
+			"EPOLLONESHOT" => EPOLLONESHOT,
+			"EPOLLEXCLUSIVE" => EPOLLEXCLUSIVE,
+			other => bail!("unsupported epoll flag `{}`", other),
+		};
 
-		let thread_state = state.clone();
-		thread::spawn(move || {
-			let mut thread_state = thread_state.lock().unwrap();
+		Ok(mask | flag as u32)
+	})
+}
 
-			let epoll_fd = handle_errno!(epoll_create1(O_CLOEXEC));
-			let mut event = epoll_event {
-				events: EPOLLIN as u32,
-				u64: 0,
-			};
+/// Parses a flag's (possibly fractional) number of seconds.
+fn parse_secs(flag: &str, arg: Option<String>) -> Result<Duration> {
+	let secs = arg
+		.and_then(|arg| arg.parse::<f64>().ok())
+		.filter(|secs| *secs >= 0.0 && secs.is_finite())
+		.with_context(|| {
+			format!("{} expects a non-negative number of seconds", flag)
+		})?;
 
-			handle_errno!(
-				epoll_ctl(
-					epoll_fd,
-					EPOLL_CTL_ADD,
-					thread_state.file.as_raw_fd(),
-					&mut event
-				),
-				epoll_wait(epoll_fd, [event].as_mut_ptr(), MAX_EVENTS, -1)
-			);
-
-			let mut buf = String::new();
-
-			thread_state.complete = true;
-			thread_state.file.read_to_string(&mut buf).unwrap();
-
-			// If this were an &'a str and PipeWriteListenState and therefore
-			// PipeWriteListen would be constricted to the lifetime 'a.
-			// This is an issue because `PipeWriteListen::new()` (which
-			// would return PipeWriteListen bound to the 'a lifetime) spawns
-			// a thread which captures a cloned arc referencing the variable
-			// representing PipeWriteListenState<'a>. since that spawned thread
-			// may live longer than `PipeWriteListen::new()`, if the closure
-			// passed to `thread::spawn` was not bound to 'static, there
-			// is a possibility of a use-after-free bug (since the thread
-			// would be trying to reference memory the memory which was freed
-			// when `PipeWriteListen::new()` went out of scope).
-			thread_state.content = Some(buf.trim().to_string());
-
-			if let Some(waker) = thread_state.waker.take() {
-				waker.wake()
+	Ok(Duration::from_secs_f64(secs))
+}
+
+fn parse_args() -> Result<Cli> {
+	let mut cli = Cli::default();
+
+	let mut args = env::args().skip(1);
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"-h" | "--help" => {
+				eprintln!("{}", HELP);
+				process::exit(1);
 			}
-		});
+			"--follow" => cli.follow = true,
+			"--count-only" => cli.count_only = true,
+			"--max-messages" => {
+				cli.max_messages = Some(
+					args.next()
+						.context("--max-messages expects a message count")?
+						.parse()
+						.context(
+							"--max-messages expects a non-negative integer",
+						)?,
+				);
+			}
+			"--output" => {
+				let path = args.next().context("--output expects a path")?;
+				let target = match path.as_str() {
+					"-" => Target::Stdout,
+					_ => Target::File(path.into()),
+				};
+				cli.outputs.push(Output::new(target, cli.on_sink_error));
+			}
+			"--syslog" => {
+				let priority = args
+					.next()
+					.context("--syslog expects <facility>.<level>")?;
+				cli.outputs.push(Output::new(
+					Target::Syslog(parse_syslog_priority(&priority)?),
+					cli.on_sink_error,
+				));
+			}
+			"--output-dir" => {
+				let dir =
+					args.next().context("--output-dir expects a directory")?;
+				cli.outputs.push(Output::new(
+					Target::Dir(dir.into()),
+					cli.on_sink_error,
+				));
+			}
+			"--sink" => {
+				let spec = args.next().context("--sink expects a sink spec")?;
+				cli.outputs.push(parse_sink(&spec, cli.on_sink_error)?);
+			}
+			"--rotate-interval" => {
+				cli.rotate_interval = Some(parse_interval(
+					&args
+						.next()
+						.context("--rotate-interval expects an interval")?,
+				)?);
+			}
+			"--on-sink-error" => {
+				let policy = match args
+					.next()
+					.context("--on-sink-error expects abort or continue")?
+					.as_str()
+				{
+					"abort" => OnSinkError::Abort,
+					"continue" => OnSinkError::Continue,
+					other => bail!(
+						"unknown sink error policy `{}`, expected abort or continue",
+						other
+					),
+				};
+				match cli.outputs.last_mut() {
+					Some(output) => output.on_error = policy,
+					None => cli.on_sink_error = policy,
+				}
+			}
+			"--mmap" => cli.mmap = true,
+			"--control" => {
+				cli.control = Some(
+					args.next().context("--control expects a path")?.into(),
+				);
+			}
+			"--compress" => {
+				cli.compress = Some(
+					match args
+						.next()
+						.context("--compress expects gzip or zstd")?
+						.as_str()
+					{
+						"gzip" => Compression::Gzip,
+						"zstd" => Compression::Zstd,
+						other => bail!(
+							"unknown codec `{}`, expected gzip or zstd",
+							other
+						),
+					},
+				);
+			}
+			"--ack-pipe" => {
+				cli.ack_pipe = Some(
+					args.next().context("--ack-pipe expects a path")?.into(),
+				);
+			}
+			"--ack-token" => {
+				cli.ack_token =
+					Some(args.next().context("--ack-token expects a line")?);
+			}
+			"--eof-marker" => {
+				cli.eof_marker =
+					Some(args.next().context("--eof-marker expects a line")?);
+			}
+			"--exec" => {
+				cli.exec =
+					Some(args.next().context("--exec expects a command")?);
+			}
+			"--exec-persistent" => cli.exec_persistent = true,
+			"--echo" => {
+				cli.echo.get_or_insert_with(|| DEFAULT_ECHO_PREFIX.into());
+			}
+			"--tag" => {
+				let tag =
+					args.next().context("--tag expects <path>=<alias>")?;
+				match tag.rsplit_once('=') {
+					Some((path, alias)) if !path.is_empty() => {
+						cli.tags.push((path.into(), alias.into()))
+					}
+					_ => bail!("--tag expects <path>=<alias>, got `{}`", tag),
+				}
+			}
+			"--echo-prefix" => {
+				cli.echo = Some(
+					args.next().context("--echo-prefix expects a prefix")?,
+				);
+			}
+			"--merge-order" => {
+				cli.opts.merge_order =
+					parse_merge_order(&args.next().context(
+						"--merge-order expects fd, bytes or roundrobin",
+					)?)?;
+			}
+			"--min-writers" => {
+				cli.opts.min_writers = args
+					.next()
+					.context("--min-writers expects a writer count")?
+					.parse()
+					.context("--min-writers expects a non-negative integer")?;
+			}
+			"--skip" => {
+				cli.opts.skip = args
+					.next()
+					.context("--skip expects a message count")?
+					.parse()
+					.context("--skip expects a non-negative integer")?;
+			}
+			"--open-timeout" => {
+				cli.opts.open_timeout =
+					Some(parse_secs("--open-timeout", args.next())?);
+			}
+			"--cpu" => {
+				let cpu = args
+					.next()
+					.context("--cpu expects a core index")?
+					.parse::<usize>()
+					.context("--cpu expects a non-negative integer")?;
 
-		PipeWriteListen { state }
+				let allowed = allowed_cpus()
+					.context("couldn't read the CPU affinity mask")?;
+				if !allowed.contains(&cpu) {
+					let list: Vec<_> =
+						allowed.iter().map(|cpu| cpu.to_string()).collect();
+					bail!(
+						"--cpu {} is not allowed, this process may only run on \
+						 CPUs {}",
+						cpu,
+						list.join(",")
+					);
+				}
+				cli.opts.cpu = Some(cpu);
+			}
+			"--events" => {
+				cli.opts.events = Some(parse_events(
+					&args.next().context("--events expects a list of flags")?,
+				)?);
+			}
+			"--framing" => {
+				cli.opts.framing = parse_framing(&args.next().context(
+					"--framing expects session, lines, delimiter:<c> or \
+					 length-prefixed:<e>:<w>",
+				)?)?;
+			}
+			"--drain-on-start" => cli.opts.drain_on_start = true,
+			"--message-per-read" => cli.opts.framing = Framing::PerRead,
+			"--verify-checksum" => cli.opts.verify_checksum = true,
+			"--secret" => cli.opts.secret = true,
+			"--debug-events" => cli.debug_events = true,
+			"--template" => {
+				cli.template =
+					Some(args.next().context("--template expects a template")?);
+			}
+			"--latest-only" => cli.opts.latest_only = true,
+			"--show-rate" => {
+				cli.show_rate.get_or_insert(DEFAULT_RATE_WINDOW);
+			}
+			"--rate-window" => {
+				cli.show_rate = Some(parse_interval(
+					&args
+						.next()
+						.context("--rate-window expects an interval")?,
+				)?);
+			}
+			"--latest-interval" => {
+				cli.latest_interval =
+					Some(parse_secs("--latest-interval", args.next())?);
+			}
+			"--seqpacket" => cli.opts.backend = Backend::SeqPacket,
+			"--encoding" => {
+				cli.opts.encoding = match args
+					.next()
+					.context("--encoding expects an encoding")?
+					.as_str()
+				{
+					"utf-8" => Encoding::Utf8,
+					"utf-16le" => Encoding::Utf16Le,
+					"utf-16be" => Encoding::Utf16Be,
+					"latin1" => Encoding::Latin1,
+					other => bail!(
+						"unknown encoding `{}`, expected utf-8, utf-16le, utf-16be or latin1",
+						other
+					),
+				};
+			}
+			"--batch" => {
+				cli.batch = Some(
+					args.next()
+						.and_then(|n| n.parse::<usize>().ok())
+						.filter(|n| *n > 0)
+						.context("--batch expects a positive integer")?,
+				);
+			}
+			"--batch-timeout" => {
+				cli.batch_timeout =
+					Some(parse_secs("--batch-timeout", args.next())?);
+			}
+			"--batch-separator" => {
+				cli.batch_separator = Some(
+					args.next()
+						.context("--batch-separator expects a string")?
+						.replace("\\n", "\n")
+						.replace("\\t", "\t"),
+				);
+			}
+			"--min-size" => {
+				cli.min_size = Some(
+					args.next()
+						.context("--min-size expects a byte count")?
+						.parse()
+						.context("--min-size expects a non-negative integer")?,
+				);
+			}
+			"--max-size" => {
+				cli.max_size = Some(
+					args.next()
+						.context("--max-size expects a byte count")?
+						.parse()
+						.context("--max-size expects a non-negative integer")?,
+				);
+			}
+			"--max-bytes" => {
+				cli.opts.max_bytes = Some(
+					args.next()
+						.context("--max-bytes expects a byte count")?
+						.parse()
+						.context(
+							"--max-bytes expects a non-negative integer",
+						)?,
+				);
+			}
+			"--max-lag" => {
+				cli.opts.max_lag = Some(
+					args.next()
+						.context("--max-lag expects a byte count")?
+						.parse()
+						.context("--max-lag expects a non-negative integer")?,
+				);
+			}
+			"--on-lag" => {
+				cli.opts.on_lag = match args
+					.next()
+					.context("--on-lag expects warn or abort")?
+					.as_str()
+				{
+					"warn" => Lag::Warn,
+					"abort" => Lag::Abort,
+					other => bail!(
+						"unknown lag policy `{}`, expected warn or abort",
+						other
+					),
+				};
+			}
+			"--on-oversize" => {
+				cli.opts.on_oversize = match args
+					.next()
+					.context("--on-oversize expects error, truncate or skip")?
+					.as_str()
+				{
+					"error" => Oversize::Error,
+					"truncate" => Oversize::Truncate,
+					"skip" => Oversize::Skip,
+					other => bail!(
+						"unknown oversize policy `{}`, expected error, truncate or skip",
+						other
+					),
+				};
+			}
+			#[cfg(feature = "daemon")]
+			"--daemonize" => cli.daemonize = true,
+			#[cfg(feature = "daemon")]
+			"--pidfile" => {
+				cli.pidfile = Some(
+					args.next().context("--pidfile expects a path")?.into(),
+				);
+			}
+			#[cfg(not(feature = "daemon"))]
+			"--daemonize" | "--pidfile" => {
+				bail!(
+					"{} needs pipe-poll to be built with the `daemon` feature",
+					arg
+				)
+			}
+			_ if arg.starts_with("--") => {
+				bail!("Unknown option {}, see --help", arg)
+			}
+			_ => cli.pipes.push(arg),
+		}
 	}
-}
 
-#[async_std::main]
-async fn main() -> Result<()> {
-	let pipe = env::args().nth(1).with_context(|| {
-		format!(
+	let mut pipes = Vec::new();
+	for pipe in cli.pipes.drain(..) {
+		if !pipe.contains(['*', '?', '[']) {
+			pipes.push(pipe);
+			continue;
+		}
+		if cli.opts.backend == Backend::SeqPacket {
+			bail!("--seqpacket creates its sockets, it can't take a glob");
+		}
+		pipes.extend(expand_glob(&pipe)?);
+	}
+	cli.pipes = pipes;
+
+	if cli.pipes.is_empty() {
+		bail!(
 			"Please pass the location of a shared pipe as argv[1]!{}",
 			HELP
-		)
-	})?;
+		);
+	}
+
+	Ok(cli)
+}
+
+/// The FIFOs matching `pattern`, with `glob(3)`. Anything else it matches
+/// is left out with a warning.
+fn expand_glob(pattern: &str) -> Result<Vec<String>> {
+	let c_pattern = CString::new(pattern)
+		.with_context(|| format!("invalid pattern `{}`", pattern))?;
+	let mut matches = MaybeUninit::<libc::glob_t>::zeroed();
+	let res = unsafe {
+		libc::glob(c_pattern.as_ptr(), 0, None, matches.as_mut_ptr())
+	};
+	let mut matches = unsafe { matches.assume_init() };
+	let paths = match res {
+		0 => (0..matches.gl_pathc)
+			.map(|i| unsafe { CStr::from_ptr(*matches.gl_pathv.add(i)) })
+			.map(|path| path.to_string_lossy().into_owned())
+			.collect(),
+		libc::GLOB_NOMATCH => Vec::new(),
+		_ => {
+			unsafe { libc::globfree(&mut matches) };
+			bail!("Couldn't expand `{}`", pattern);
+		}
+	};
+	unsafe { libc::globfree(&mut matches) };
+
+	let fifos: Vec<_> = paths
+		.into_iter()
+		.filter(|path| {
+			let fifo =
+				fs::metadata(path).is_ok_and(|meta| meta.file_type().is_fifo());
+			if !fifo {
+				eprintln!("Skipping {}, which isn't a named pipe", path);
+			}
+			fifo
+		})
+		.collect();
+	if fifos.is_empty() {
+		bail!("No named pipes match `{}`", pattern);
+	}
+	Ok(fifos)
+}
+
+#[cfg(feature = "daemon")]
+impl Cli {
+	/// The daemon changes into `/`, so every path has to be absolute by then.
+	fn make_paths_absolute(&mut self) -> Result<()> {
+		let cwd =
+			env::current_dir().context("Couldn't get working directory")?;
+
+		for pipe in &mut self.pipes {
+			*pipe = cwd.join(&pipe).to_string_lossy().into_owned();
+		}
+		for output in &mut self.outputs {
+			match &mut output.target {
+				Target::File(path) | Target::Dir(path) => {
+					*path = cwd.join(&path)
+				}
+				Target::Stdout | Target::Syslog(_) => {}
+			}
+		}
+		for (path, _) in &mut self.tags {
+			*path = cwd.join(&path);
+		}
+		if let Some(ack_pipe) = &mut self.ack_pipe {
+			*ack_pipe = cwd.join(&ack_pipe);
+		}
+		if let Some(control) = &mut self.control {
+			*control = cwd.join(&control);
+		}
+		if let Some(pidfile) = &mut self.pidfile {
+			*pidfile = cwd.join(&pidfile);
+		}
+
+		Ok(())
+	}
+}
+
+fn main() -> Result<()> {
+	if env::args().nth(1).as_deref() == Some("bench-latency") {
+		return task::block_on(bench_latency());
+	}
+	if env::args().nth(1).as_deref() == Some("check-framing") {
+		return check_framing();
+	}
+
+	#[allow(unused_mut)]
+	let mut cli = parse_args()?;
+
+	// This has to happen before the async runtime starts its threads, which
+	// wouldn't survive the forks.
+	#[cfg(feature = "daemon")]
+	{
+		if cli.daemonize {
+			if !(cli.follow || cli.count_only) {
+				bail!("--daemonize needs --follow or --count-only");
+			}
+			// The daemon's stdout is /dev/null.
+			if cli
+				.outputs
+				.iter()
+				.all(|o| matches!(o.target, Target::Stdout))
+			{
+				bail!(
+					"--daemonize needs an --output, --output-dir or --sink \
+					 other than stdout"
+				);
+			}
+			cli.make_paths_absolute()?;
+			let daemon = pipe_poll::daemonize(cli.pidfile.as_deref())
+				.context("Couldn't daemonize")?;
+			*DAEMON.lock().unwrap() = Some(daemon);
+		}
+	}
+
+	let res = task::block_on(run(cli));
+	// Dropping the daemon removes the pidfile.
+	#[cfg(feature = "daemon")]
+	if let Some(mut daemon) = DAEMON.lock().unwrap().take() {
+		if let Err(e) = &res {
+			daemon.fail(&format!("{:#}", e));
+		}
+	}
+	res
+}
+
+/// Starts watching `pipes`, answering `--control` queries about them if
+/// asked to.
+fn watch(
+	pipes: &[String],
+	opts: &ListenOptions,
+	control: Option<&Path>,
+) -> Result<(Arc<PipeWatcher>, Option<Control>)> {
+	let watcher = Arc::new(
+		PipeWatcher::open_all(pipes, opts)
+			.with_context(|| format!("Couldn't watch {}", pipes.join(", ")))?,
+	);
+	let control = control
+		.map(|path| {
+			control::serve(path, watcher.clone()).with_context(|| {
+				format!("Couldn't listen on {}", path.display())
+			})
+		})
+		.transpose()?;
+
+	#[cfg(feature = "daemon")]
+	if let Some(daemon) = DAEMON.lock().unwrap().as_mut() {
+		daemon.ready();
+	}
+	Ok((watcher, control))
+}
+
+/// Writes the `--ack-token` line to the `--ack-pipe`, waiting for the
+/// producer to open it for reading.
+async fn acknowledge(ack: Option<&(PathBuf, String)>) -> Result<()> {
+	if let Some((path, token)) = ack {
+		write_to_pipe(path, &format!("{}\n", token))
+			.await
+			.with_context(|| {
+				format!("Couldn't acknowledge on {}", path.display())
+			})?;
+	}
+	Ok(())
+}
+
+async fn run(cli: Cli) -> Result<()> {
+	let Cli {
+		follow,
+		count_only,
+		max_messages,
+		mut opts,
+		pipes,
+		mut outputs,
+		rotate_interval,
+		latest_interval,
+		show_rate,
+		debug_events,
+		min_size,
+		max_size,
+		batch,
+		batch_timeout,
+		batch_separator,
+		template,
+		on_sink_error,
+		mmap,
+		control,
+		compress,
+		eof_marker,
+		ack_pipe,
+		ack_token,
+		exec,
+		exec_persistent,
+		echo,
+		tags,
+		..
+	} = cli;
+
+	// Indexed like `pipes`, i.e. by epoll tag.
+	let mut aliases = vec![None; pipes.len()];
+	for (path, alias) in tags {
+		let i = pipes
+			.iter()
+			.position(|pipe| Path::new(pipe) == path)
+			.with_context(|| {
+				format!("--tag {} isn't one of the pipes", path.display())
+			})?;
+		aliases[i] = Some(alias);
+	}
+
+	if mmap {
+		if compress.is_some() || exec.is_some() {
+			bail!("--mmap can't be combined with --compress or --exec");
+		}
+		opts.mmap_output = match outputs.as_slice() {
+			[Output {
+				target: Target::File(path),
+				format: None | Some(Format::Plain),
+				compress: None,
+				grep: None,
+				..
+			}] if template.is_none() => Some(path.clone()),
+			_ => bail!("--mmap needs a single plain --output file"),
+		};
+		outputs.clear();
+	}
+	if outputs.is_empty() {
+		outputs.push(Output::new(Target::Stdout, on_sink_error));
+	}
+	let plain = |o: &Output| {
+		matches!(o.target, Target::Stdout | Target::File(_))
+			&& matches!(o.format, None | Some(Format::Plain))
+			&& o.compress.is_none()
+			&& o.grep.is_none()
+	};
+	if opts.secret
+		&& (exec.is_some()
+			|| batch.is_some()
+			|| echo.is_some()
+			|| template.is_some()
+			|| compress.is_some()
+			|| mmap || !outputs.iter().all(plain))
+	{
+		bail!(
+			"--secret only writes to plain stdout or --output files, without \
+			 --exec, --batch, --echo, --template, --compress or --mmap"
+		);
+	}
+	let has_dir = outputs.iter().any(|o| matches!(o.target, Target::Dir(_)));
+	if rotate_interval.is_some() && !has_dir {
+		bail!("--rotate-interval needs --output-dir");
+	}
+
+	let filter_size = min_size.is_some() || max_size.is_some();
+	if filter_size && (!follow || mmap) {
+		bail!("--min-size and --max-size need --follow, without --mmap");
+	}
+	let wanted_size = |len| {
+		min_size.map_or(true, |min| len >= min)
+			&& max_size.map_or(true, |max| len <= max)
+	};
+
+	if batch.is_some() && (!follow || mmap) {
+		bail!("--batch needs --follow, without --mmap");
+	}
+	if batch.is_none() && (batch_timeout.is_some() || batch_separator.is_some())
+	{
+		bail!("--batch-timeout and --batch-separator need --batch");
+	}
+
+	if echo.is_some() {
+		if !follow || mmap || opts.backend != Backend::Fifo {
+			bail!("--echo needs --follow, without --mmap or --seqpacket");
+		}
+		match opts.framing {
+			Framing::Session | Framing::PerRead => {}
+			Framing::Delimiter(delim) if delim.is_ascii() => {}
+			_ => bail!("--echo needs session framing or an ASCII delimiter"),
+		}
+		if opts.encoding != Encoding::Utf8 {
+			bail!("--echo needs UTF-8");
+		}
+	}
+
+	if matches!(opts.encoding, Encoding::Utf16Le | Encoding::Utf16Be)
+		&& matches!(opts.framing, Framing::Delimiter(_) | Framing::PerRead)
+	{
+		bail!(
+			"--encoding utf-16le/utf-16be need session or length-prefixed \
+			 framing, without --message-per-read"
+		);
+	}
+
+	if latest_interval.is_some() && !(follow && opts.latest_only) {
+		bail!("--latest-interval needs --follow and --latest-only");
+	}
+
+	let ack = match (ack_pipe, ack_token) {
+		(Some(path), token) => {
+			Some((path, token.unwrap_or_else(|| "ack".to_string())))
+		}
+		(None, Some(_)) => bail!("--ack-token needs --ack-pipe"),
+		(None, None) => None,
+	};
+
+	let mut exec = match exec {
+		Some(cmd) => Some(
+			Exec::new(cmd, exec_persistent)
+				.context("Couldn't start the --exec command")?,
+		),
+		None if exec_persistent => bail!("--exec-persistent needs --exec"),
+		None => None,
+	};
+
+	let mut tee = Tee::default();
+	for output in outputs {
+		let compress = output.compress.or(compress);
+		let (name, sink) = match output.target {
+			Target::Stdout if opts.secret => {
+				("stdout".to_string(), Box::new(WriteSink(RawStdout)) as _)
+			}
+			Target::Stdout => (
+				"stdout".to_string(),
+				writer_sink(Box::new(io::stdout()), compress)?,
+			),
+			Target::File(path) => {
+				let file = OpenOptions::new()
+					.create(true)
+					.append(true)
+					.open(&path)
+					.with_context(|| {
+						format!("Couldn't open {}", path.display())
+					})?;
+				(
+					path.display().to_string(),
+					writer_sink(Box::new(file), compress)?,
+				)
+			}
+			Target::Dir(dir) => {
+				let extension = match compress {
+					Some(compression) => {
+						format!("log.{}", compression.extension())
+					}
+					None => "log".to_string(),
+				};
+				let rotating = Rotating::new(
+					dir.clone(),
+					extension,
+					rotate_interval.unwrap_or(DEFAULT_ROTATE_INTERVAL),
+					Box::new(move |file| {
+						writer_sink(Box::new(file), compress)
+							.map_err(io::Error::other)
+					}),
+				);
+				(dir.display().to_string(), Box::new(rotating) as _)
+			}
+			Target::Syslog(priority) => {
+				if compress.is_some() {
+					bail!("syslog outputs can't be compressed");
+				}
+				("syslog".to_string(), Box::new(Syslog::new(priority)) as _)
+			}
+		};
+		if opts.secret {
+			// Formatting would leave copies behind.
+			tee.push(name, sink, output.on_error);
+			continue;
+		}
+		let sink = Box::new(Formatted {
+			format: output
+				.format
+				.or_else(|| template.clone().map(Format::Template))
+				.unwrap_or_default(),
+			grep: output.grep,
+			inner: sink,
+		});
+		tee.push(name, sink, output.on_error);
+	}
+	let mut sink = tee;
+
+	let reached_max = |n| max_messages.is_some_and(|max| n >= max);
+	if debug_events && (!follow || mmap) {
+		bail!("--debug-events needs --follow, without --mmap");
+	}
+	if show_rate.is_some() && !(follow || count_only) {
+		bail!("--show-rate needs --follow or --count-only");
+	}
+	let mut rate = show_rate.map(RateMeter::new);
+	let mut show_rate = |watcher: &PipeWatcher| {
+		if let Some(report) =
+			rate.as_mut().and_then(|r| r.tick(watcher.totals()))
+		{
+			eprintln!("{}", report);
+		}
+	};
+
+	if count_only {
+		handle_signals();
+
+		// The totals would otherwise take in whatever arrives while this
+		// loop sleeps.
+		opts.max_messages = max_messages;
+		let (watcher, _control) = watch(&pipes, &opts, control.as_deref())?;
+		let mut stopping = false;
+		loop {
+			if !stopping && SHUTDOWN.load(Ordering::SeqCst) {
+				watcher.shutdown();
+				stopping = true;
+			}
+			if !stopping {
+				apply_pause(&watcher);
+			}
+			show_rate(&watcher);
+			// Only the totals matter, but taking the messages is what frees
+			// them, and fatal errors arrive the same way.
+			match future::timeout(SHUTDOWN_POLL, watcher.next()).await {
+				Err(_) => {}
+				Ok(Ok(msg)) if opts.secret => drop(msg.into_secret()),
+				Ok(Ok(_)) => {}
+				Ok(Err(
+					e @ (PipeError::ReadFailed(_)
+					| PipeError::ChecksumMismatch { .. }),
+				)) => eprintln!("{}", e),
+				Ok(Err(e @ PipeError::Lagging { .. }))
+					if opts.on_lag == Lag::Warn =>
+				{
+					eprintln!("Warning: {}", e)
+				}
+				// Stopped, or done at --max-messages.
+				Ok(Err(PipeError::Cancelled)) => break,
+				Ok(Err(e)) => {
+					watcher.shutdown();
+					return Err(e.into());
+				}
+			}
+		}
 
-	if pipe == "-h" || pipe == "--help" {
-		eprintln!("{}", HELP);
-		process::exit(1);
+		let (messages, bytes) = watcher.totals();
+		sink.send(&format!("{} messages, {} bytes", messages, bytes))?;
+		if let Some(marker) = &eof_marker {
+			sink.send(marker)?;
+		}
+		sink.finish()?;
+		return Ok(());
 	}
 
+	if follow {
+		handle_signals();
+
+		let (watcher, _control) = watch(&pipes, &opts, control.as_deref())?;
+		let mut batch =
+			batch.map(|size| Batch::new(size, batch_timeout, batch_separator));
+		let mut received = 0;
+		let mut stopping = false;
+		while !reached_max(received) {
+			if !stopping && SHUTDOWN.load(Ordering::SeqCst) {
+				// Stop reading, but still write out everything that's been
+				// read already; the watcher hands that out before it
+				// reports being cancelled.
+				watcher.shutdown();
+				stopping = true;
+			}
+			if !stopping {
+				apply_pause(&watcher);
+			}
+			show_rate(&watcher);
+			let deadline = batch.as_ref().and_then(Batch::deadline);
+			let wait = deadline.map_or(SHUTDOWN_POLL, |deadline| {
+				deadline
+					.saturating_duration_since(Instant::now())
+					.min(SHUTDOWN_POLL)
+			});
+			let msg = match future::timeout(wait, watcher.next()).await {
+				Err(_) => {
+					if deadline.is_some_and(|d| d <= Instant::now()) {
+						let batch = batch.as_mut().unwrap();
+						flush_batch(batch, &mut sink, &watcher, ack.as_ref())
+							.await?;
+					}
+					continue;
+				}
+				Ok(Ok(msg)) => msg,
+				Ok(Err(
+					e @ (PipeError::ReadFailed(_)
+					| PipeError::ChecksumMismatch { .. }),
+				)) => {
+					eprintln!("{}", e);
+					continue;
+				}
+				Ok(Err(e @ PipeError::Lagging { .. }))
+					if opts.on_lag == Lag::Warn =>
+				{
+					eprintln!("Warning: {}", e);
+					continue;
+				}
+				Ok(Err(PipeError::Cancelled)) if stopping => break,
+				Ok(Err(e)) => {
+					watcher.shutdown();
+					return Err(e.into());
+				}
+			};
+			if mmap {
+				acknowledge(ack.as_ref()).await?;
+				received += 1;
+				continue;
+			}
+			if opts.secret {
+				let secret = msg.into_secret();
+				sink.send(secret.as_str())?;
+				for failure in sink.take_failures() {
+					watcher.log_error(failure);
+				}
+				acknowledge(ack.as_ref()).await?;
+				received += 1;
+				continue;
+			}
+			if let Some(prefix) = &echo {
+				if msg.content.starts_with(prefix.as_str()) {
+					// One of our own echoes coming back round.
+					continue;
+				}
+
+				let pipe = &pipes[msg.source];
+				let mut line = format!("{}{}", prefix, msg.content);
+				if let Framing::Delimiter(delim) = opts.framing {
+					line.push(delim as char);
+				}
+				if let Err(e) = write_to_pipe(pipe, &line).await {
+					eprintln!("Couldn't echo to {}: {}", pipe, e);
+					watcher.log_error(format!("--echo to {}: {}", pipe, e));
+				}
+			}
+			if !wanted_size(msg.len) {
+				acknowledge(ack.as_ref()).await?;
+				continue;
+			}
+			let content = match &mut exec {
+				Some(exec) => match exec.transform(&msg.content) {
+					Ok(content) => content,
+					Err(e) => {
+						eprintln!("--exec failed: {}", e);
+						watcher.log_error(format!("--exec failed: {}", e));
+						acknowledge(ack.as_ref()).await?;
+						continue;
+					}
+				},
+				None => msg.content,
+			};
+			let source =
+				Some(pipes[msg.source].as_str()).filter(|_| pipes.len() > 1);
+			let tag = aliases[msg.source].as_deref();
+			let checksum = msg.checksum;
+			let events = Some(msg.events).filter(|_| debug_events);
+			received += 1;
+			if let Some(batch) = &mut batch {
+				batch.push(match tag.or(source) {
+					Some(source) => format!("{}: {}", source, content),
+					None => content,
+				});
+				if batch.is_full() || reached_max(received) {
+					flush_batch(batch, &mut sink, &watcher, ack.as_ref())
+						.await?;
+				}
+				continue;
+			}
+			sink.emit(&Received {
+				source,
+				tag,
+				content: &content,
+				checksum,
+				events,
+			})?;
+			for failure in sink.take_failures() {
+				watcher.log_error(failure);
+			}
+			acknowledge(ack.as_ref()).await?;
+
+			if let Some(interval) = latest_interval {
+				// Whatever arrives meanwhile is coalesced by --latest-only.
+				let until = Instant::now() + interval;
+				while !SHUTDOWN.load(Ordering::SeqCst) {
+					match until.checked_duration_since(Instant::now()) {
+						Some(left) => {
+							task::sleep(left.min(SHUTDOWN_POLL)).await
+						}
+						None => break,
+					}
+				}
+			}
+		}
+		if let Some(batch) = &mut batch {
+			flush_batch(batch, &mut sink, &watcher, ack.as_ref()).await?;
+		}
+		watcher.shutdown();
+		if let Some(marker) = &eof_marker {
+			sink.send(marker)?;
+		}
+		sink.finish()?;
+
+		return Ok(());
+	}
+
+	let listen = PipeWriteListen::new(&pipes, &opts)
+		.with_context(|| format!("Couldn't watch {}", pipes.join(", ")))?;
+	let secret = opts.secret;
 	task::spawn(async move {
-		let written = PipeWriteListen::new(&pipe).await;
-		println!("Thread finished its job with: {}!", written);
-		process::exit(0);
+		match listen.await {
+			Ok(written) if secret => {
+				let written = Secret::from(written);
+				let res = sink
+					.send(written.as_str())
+					.and_then(|_| match &eof_marker {
+						Some(marker) => sink.send(marker),
+						None => Ok(()),
+					})
+					.and_then(|_| sink.finish());
+				drop(written);
+				if let Err(e) = res {
+					eprintln!("Error: {}", e);
+					process::exit(1);
+				}
+				if let Err(e) = acknowledge(ack.as_ref()).await {
+					eprintln!("Error: {:#}", e);
+					process::exit(1);
+				}
+				process::exit(0);
+			}
+			Ok(written) => {
+				let res = match &mut exec {
+					Some(exec) => exec.transform(&written),
+					None => Ok(written),
+				}
+				.and_then(|written| {
+					sink.send(&format!(
+						"Thread finished its job with: {}!",
+						written
+					))
+				})
+				.and_then(|_| match &eof_marker {
+					Some(marker) => sink.send(marker),
+					None => Ok(()),
+				})
+				.and_then(|_| sink.finish());
+				if res.is_ok() {
+					if let Err(e) = acknowledge(ack.as_ref()).await {
+						eprintln!("Error: {:#}", e);
+						process::exit(1);
+					}
+				}
+				process::exit(if res.is_ok() { 0 } else { 1 });
+			}
+			Err(e) => {
+				eprintln!("Error: {}", e);
+				process::exit(1);
+			}
+		}
 	});
 
 	let mut seconds = 0;
@@ -184,3 +1635,275 @@ async fn main() -> Result<()> {
 		seconds += 1;
 	}
 }
+
+/// Writes out what's in `batch`, then acknowledges each of its messages.
+async fn flush_batch(
+	batch: &mut Batch,
+	sink: &mut Tee,
+	watcher: &PipeWatcher,
+	ack: Option<&(PathBuf, String)>,
+) -> Result<()> {
+	let len = batch.len();
+	let line = match batch.take() {
+		Some(line) => line,
+		None => return Ok(()),
+	};
+
+	sink.emit(&Received::other(&line))?;
+	for failure in sink.take_failures() {
+		watcher.log_error(failure);
+	}
+	for _ in 0..len {
+		acknowledge(ack).await?;
+	}
+	Ok(())
+}
+
+/// Wraps a plain output, compressing it if asked to.
+fn writer_sink(
+	out: Box<dyn Write + Send>,
+	compress: Option<Compression>,
+) -> Result<Box<dyn MessageSink>> {
+	Ok(match compress {
+		Some(compression) => Box::new(
+			Compressed::new(compression, out)
+				.context("Couldn't start the compressor")?,
+		),
+		None => Box::new(WriteSink(out)),
+	})
+}
+
+/// `pipe-poll bench-latency`: times write/echo round trips through a pair of
+/// pipes. The reply side is watched for the whole run, rather than going
+/// through `pipe_poll::request` each time, and requests are written on this
+/// thread, rather than with `write_to_pipe` and the thread it spawns, so
+/// per-iteration setup doesn't end up in the measurements.
+async fn bench_latency() -> Result<()> {
+	let mut iterations = 1000;
+	let mut pipes = Vec::new();
+
+	let mut args = env::args().skip(2);
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"--iterations" => {
+				iterations = args
+					.next()
+					.and_then(|n| n.parse::<usize>().ok())
+					.filter(|n| *n > 0)
+					.context("--iterations expects a positive integer")?;
+			}
+			_ if arg.starts_with("--") => {
+				bail!("Unknown option {}, see --help", arg)
+			}
+			_ => pipes.push(arg),
+		}
+	}
+
+	let (req, resp) = match pipes.as_slice() {
+		[req, resp] => (req, resp),
+		_ => bail!("bench-latency expects a request and a response pipe"),
+	};
+
+	let is_fifo = fs::metadata(req)
+		.with_context(|| format!("Couldn't write to {}", req))?
+		.file_type()
+		.is_fifo();
+	if !is_fifo {
+		bail!("{} isn't a named pipe", req);
+	}
+	let replies = PipeWatcher::open(resp)
+		.with_context(|| format!("Couldn't watch {}", resp))?;
+	let mut samples = Vec::with_capacity(iterations);
+
+	for _ in 0..iterations {
+		let stamp = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_nanos()
+			.to_string();
+
+		// Nothing else runs on this task meanwhile, so blocking until the
+		// echoing side opens the pipe is fine.
+		let start = Instant::now();
+		OpenOptions::new()
+			.write(true)
+			.open(req)
+			.and_then(|mut file| file.write_all(stamp.as_bytes()))
+			.with_context(|| format!("Couldn't write to {}", req))?;
+		let echo = replies.next().await?;
+		samples.push(start.elapsed());
+
+		if echo.content != stamp {
+			bail!("Expected {} to be echoed back, got {}", stamp, echo.content);
+		}
+	}
+
+	samples.sort_unstable();
+	let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+
+	println!("{} round trips", samples.len());
+	println!("min {:?}", samples[0]);
+	println!("p50 {:?}", percentile(50));
+	println!("p90 {:?}", percentile(90));
+	println!("p99 {:?}", percentile(99));
+	println!("max {:?}", samples[samples.len() - 1]);
+
+	Ok(())
+}
+
+/// `pipe-poll check-framing`: splits stdin into frames like a pipe would be
+/// and describes them.
+fn check_framing() -> Result<()> {
+	let mut framing = None;
+
+	let mut args = env::args().skip(2);
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"--framing" => {
+				framing = Some(parse_framing(&args.next().context(
+					"--framing expects session, lines, delimiter:<c> or \
+					 length-prefixed:<e>:<w>",
+				)?)?);
+			}
+			_ => bail!("Unknown argument {}, see --help", arg),
+		}
+	}
+	let framing = framing.context("check-framing needs --framing")?;
+
+	let mut sample = Vec::new();
+	io::stdin()
+		.lock()
+		.read_to_end(&mut sample)
+		.context("Couldn't read the sample from stdin")?;
+	let len = sample.len();
+	let frames = framing.split(&mut sample, true);
+
+	println!("{} frames in {} bytes", frames.len(), len);
+	for (i, frame) in frames.iter().enumerate() {
+		println!("{}: {} bytes", i, frame.len());
+	}
+	if !sample.is_empty() {
+		bail!(
+			"{} trailing bytes don't make up a whole frame",
+			sample.len()
+		);
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn framing_specs() {
+		assert_eq!(parse_framing("session").unwrap(), Framing::Session);
+		assert_eq!(parse_framing("lines").unwrap(), Framing::Delimiter(b'\n'));
+		assert_eq!(
+			parse_framing("delimiter:\\0").unwrap(),
+			Framing::Delimiter(0)
+		);
+		assert_eq!(
+			parse_framing("delimiter:0x1e").unwrap(),
+			Framing::Delimiter(0x1e)
+		);
+		assert_eq!(
+			parse_framing("delimiter:;").unwrap(),
+			Framing::Delimiter(b';')
+		);
+		assert_eq!(
+			parse_framing("length-prefixed:be:u32").unwrap(),
+			Framing::LengthPrefixed {
+				big_endian: true,
+				width: 4
+			}
+		);
+
+		for bad in [
+			"",
+			"delimiter:",
+			"delimiter:ab",
+			"delimiter:0xzz",
+			"length-prefixed:be",
+			"length-prefixed:mid:u8",
+		] {
+			assert!(parse_framing(bad).is_err(), "{:?}", bad);
+		}
+	}
+
+	#[test]
+	fn intervals() {
+		assert_eq!(parse_interval("90").unwrap(), Duration::from_secs(90));
+		assert_eq!(parse_interval("90s").unwrap(), Duration::from_secs(90));
+		assert_eq!(parse_interval("30m").unwrap(), Duration::from_secs(1800));
+		assert_eq!(parse_interval("1h").unwrap(), Duration::from_secs(3600));
+		assert_eq!(parse_interval("2d").unwrap(), Duration::from_secs(172800));
+
+		for bad in ["", "0", "0h", "m", "5w", "1.5h", "-1"] {
+			assert!(parse_interval(bad).is_err(), "{:?}", bad);
+		}
+	}
+
+	#[test]
+	fn sink_targets() {
+		let sink = |spec| parse_sink(spec, OnSinkError::Abort).unwrap();
+		assert!(matches!(sink("stdout").target, Target::Stdout));
+		assert!(matches!(sink("-").target, Target::Stdout));
+		assert!(matches!(
+			sink("file=/tmp/out").target,
+			Target::File(path) if path == Path::new("/tmp/out")
+		));
+		assert!(matches!(sink("dir=logs").target, Target::Dir(_)));
+		assert!(matches!(
+			sink("syslog").target,
+			Target::Syslog(DEFAULT_SYSLOG_PRIORITY)
+		));
+		assert!(matches!(
+			sink("syslog=daemon.err").target,
+			Target::Syslog(p) if p == libc::LOG_DAEMON | libc::LOG_ERR
+		));
+	}
+
+	#[test]
+	fn sink_options() {
+		let output = parse_sink(
+			"file=out:json:zstd:continue:grep=x",
+			OnSinkError::Abort,
+		)
+		.unwrap();
+		assert_eq!(output.format, Some(Format::Json));
+		assert!(matches!(output.compress, Some(Compression::Zstd)));
+		assert_eq!(output.on_error, OnSinkError::Continue);
+		assert_eq!(output.grep.as_deref(), Some("x"));
+
+		// A template takes the rest of the spec, colons and all.
+		let output = parse_sink(
+			"stdout:template={source}: {message}",
+			OnSinkError::Abort,
+		)
+		.unwrap();
+		assert_eq!(
+			output.format,
+			Some(Format::Template("{source}: {message}".to_string()))
+		);
+		assert_eq!(output.on_error, OnSinkError::Abort);
+
+		for bad in ["", "file", "pipe=x", "stdout:xml", "syslog=kern.bogus"] {
+			assert!(parse_sink(bad, OnSinkError::Abort).is_err(), "{:?}", bad);
+		}
+	}
+
+	#[test]
+	fn event_masks() {
+		assert_eq!(parse_events("EPOLLIN").unwrap(), EPOLLIN as u32);
+		assert_eq!(
+			parse_events("EPOLLIN, EPOLLET,EPOLLONESHOT").unwrap(),
+			(EPOLLIN | EPOLLET | EPOLLONESHOT) as u32
+		);
+
+		for bad in ["", "EPOLLOUT", "EPOLLIN,", "epollin"] {
+			assert!(parse_events(bad).is_err(), "{:?}", bad);
+		}
+	}
+}
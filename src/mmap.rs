@@ -0,0 +1,128 @@
+/*
+ * pipe-poll
+ * Copyright (C) 2021 Safin Singh
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use libc::{
+	ftruncate, mmap, mremap, munmap, off_t, MAP_FAILED, MAP_SHARED,
+	MREMAP_MAYMOVE, PROT_READ, PROT_WRITE,
+};
+use std::{
+	cmp,
+	fs::{File, OpenOptions},
+	io,
+	os::unix::io::AsRawFd,
+	path::Path,
+	ptr,
+};
+
+/// How much the file grows by at least, so small writes don't each remap.
+const MIN_GROWTH: usize = 1 << 20;
+
+/// A file appended to through a shared memory mapping instead of `write(2)`.
+/// The file is grown ahead of the data and cut back to what was actually
+/// written when this is dropped.
+pub(crate) struct MappedFile {
+	file: File,
+	ptr: *mut u8,
+	cap: usize,
+	len: usize,
+}
+
+// The mapping is only ever touched through `&mut self`.
+unsafe impl Send for MappedFile {}
+
+impl MappedFile {
+	/// Opens `path` for appending, creating it if needed.
+	pub(crate) fn open(path: &Path) -> io::Result<Self> {
+		let file = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create(true)
+			.truncate(false)
+			.open(path)?;
+		let len = file.metadata()?.len() as usize;
+
+		Ok(MappedFile {
+			file,
+			ptr: ptr::null_mut(),
+			cap: 0,
+			len,
+		})
+	}
+
+	pub(crate) fn len(&self) -> usize {
+		self.len
+	}
+
+	pub(crate) fn extend(&mut self, data: &[u8]) -> io::Result<()> {
+		let needed = self.len + data.len();
+		if needed > self.cap {
+			self.grow(cmp::max(needed, self.cap * 2).max(MIN_GROWTH))?;
+		}
+
+		unsafe {
+			ptr::copy_nonoverlapping(
+				data.as_ptr(),
+				self.ptr.add(self.len),
+				data.len(),
+			)
+		};
+		self.len = needed;
+		Ok(())
+	}
+
+	/// Drops everything after the first `len` bytes.
+	pub(crate) fn truncate(&mut self, len: usize) {
+		self.len = cmp::min(self.len, len);
+	}
+
+	fn grow(&mut self, cap: usize) -> io::Result<()> {
+		let fd = self.file.as_raw_fd();
+		check_errno!(ftruncate(fd, cap as off_t))?;
+
+		let ptr = if self.ptr.is_null() {
+			unsafe {
+				mmap(
+					ptr::null_mut(),
+					cap,
+					PROT_READ | PROT_WRITE,
+					MAP_SHARED,
+					fd,
+					0,
+				)
+			}
+		} else {
+			unsafe { mremap(self.ptr as _, self.cap, cap, MREMAP_MAYMOVE) }
+		};
+		if ptr == MAP_FAILED {
+			return Err(io::Error::last_os_error());
+		}
+
+		self.ptr = ptr as *mut u8;
+		self.cap = cap;
+		Ok(())
+	}
+}
+
+impl Drop for MappedFile {
+	fn drop(&mut self) {
+		if !self.ptr.is_null() {
+			unsafe { munmap(self.ptr as _, self.cap) };
+		}
+		unsafe { ftruncate(self.file.as_raw_fd(), self.len as off_t) };
+	}
+}
@@ -0,0 +1,782 @@
+/*
+ * pipe-poll
+ * Copyright (C) 2021 Safin Singh
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Everything between a received message and the bytes written out.
+
+use crate::control::json_string;
+use std::{
+	ffi::CString,
+	fs::{File, OpenOptions},
+	io::{self, BufRead, BufReader, Read, Write},
+	mem::{self, MaybeUninit},
+	path::PathBuf,
+	process::{Child, ChildStdout, Command, Stdio},
+	thread::{self, JoinHandle},
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// A received message on its way out.
+pub struct Received<'a> {
+	/// The pipe it came from, when there's more than one.
+	pub source: Option<&'a str>,
+	/// That pipe's `--tag`, shown instead of `source`.
+	pub tag: Option<&'a str>,
+	pub content: &'a str,
+	pub checksum: Option<u32>,
+	/// The epoll events behind it, for `--debug-events`.
+	pub events: Option<u32>,
+}
+
+impl<'a> Received<'a> {
+	/// Something that isn't a single message, like a whole `Batch`.
+	pub fn other(content: &'a str) -> Self {
+		Received {
+			source: None,
+			tag: None,
+			content,
+			checksum: None,
+			events: None,
+		}
+	}
+}
+
+/// Where emitted messages end up.
+pub trait MessageSink: Send {
+	/// Emits one message. Sinks add their own framing, the plain ones a
+	/// trailing newline.
+	fn send(&mut self, msg: &str) -> io::Result<()>;
+
+	/// Emits a received message.
+	fn emit(&mut self, msg: &Received<'_>) -> io::Result<()> {
+		match msg.source {
+			Some(source) => self.send(&format!("{}: {}", source, msg.content)),
+			None => self.send(msg.content),
+		}
+	}
+
+	/// Writes out anything still buffered; nothing may be sent afterwards.
+	fn finish(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+/// Stdout without the buffer of `io::Stdout`, which would keep a copy of
+/// everything written through it, for `--secret`.
+pub struct RawStdout;
+
+impl Write for RawStdout {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let n = unsafe { libc::write(1, buf.as_ptr() as _, buf.len()) };
+		if n == -1 {
+			Err(io::Error::last_os_error())
+		} else {
+			Ok(n as usize)
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+/// Writes each message as a line to `W`, flushing after every one.
+pub struct WriteSink<W>(pub W);
+
+impl<W: Write + Send> MessageSink for WriteSink<W> {
+	fn send(&mut self, msg: &str) -> io::Result<()> {
+		writeln!(self.0, "{}", msg).and_then(|_| self.0.flush())
+	}
+}
+
+#[derive(Clone, Copy)]
+pub enum Compression {
+	Gzip,
+	Zstd,
+}
+
+impl Compression {
+	fn command(self) -> Command {
+		let mut command = match self {
+			Compression::Gzip => Command::new("gzip"),
+			Compression::Zstd => Command::new("zstd"),
+		};
+		command.arg("-c").arg("-q");
+		command
+	}
+
+	pub fn extension(self) -> &'static str {
+		match self {
+			Compression::Gzip => "gz",
+			Compression::Zstd => "zst",
+		}
+	}
+}
+
+/// Compresses the stream of lines the inner sink would have received into
+/// a single continuous gzip or zstd stream, e.g. for `gzip -dc`. The
+/// compressor is the external `gzip` or `zstd` binary; it decides when
+/// compressed data comes out, so output lags behind and is only complete
+/// once the sink is finished.
+pub struct Compressed {
+	child: Child,
+	pump: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl Compressed {
+	pub fn new<W: Write + Send + 'static>(
+		compression: Compression,
+		mut inner: W,
+	) -> io::Result<Self> {
+		let mut child = compression
+			.command()
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.spawn()?;
+		let mut stdout = child.stdout.take().unwrap();
+		let pump = thread::spawn(move || {
+			io::copy(&mut stdout, &mut inner)?;
+			inner.flush()
+		});
+
+		Ok(Compressed {
+			child,
+			pump: Some(pump),
+		})
+	}
+}
+
+impl MessageSink for Compressed {
+	fn send(&mut self, msg: &str) -> io::Result<()> {
+		match self.child.stdin.as_mut() {
+			Some(stdin) => writeln!(stdin, "{}", msg),
+			None => Err(io::ErrorKind::BrokenPipe.into()),
+		}
+	}
+
+	fn finish(&mut self) -> io::Result<()> {
+		let pump = match self.pump.take() {
+			Some(pump) => pump,
+			None => return Ok(()),
+		};
+
+		drop(self.child.stdin.take());
+		let status = self.child.wait()?;
+		pump.join().unwrap()?;
+		if !status.success() {
+			return Err(io::Error::other(format!(
+				"compressor exited with {}",
+				status
+			)));
+		}
+		Ok(())
+	}
+}
+
+impl Drop for Compressed {
+	fn drop(&mut self) {
+		let _ = self.finish();
+	}
+}
+
+/// Sends each message to the local syslog daemon, which adds the time and
+/// `pipe-poll[<pid>]`. `priority` is a facility and a level or'ed together.
+pub struct Syslog {
+	priority: libc::c_int,
+}
+
+impl Syslog {
+	pub fn new(priority: libc::c_int) -> Self {
+		static IDENT: &[u8] = b"pipe-poll\0";
+		unsafe { libc::openlog(IDENT.as_ptr() as _, libc::LOG_PID, 0) };
+		Syslog { priority }
+	}
+}
+
+impl MessageSink for Syslog {
+	fn send(&mut self, msg: &str) -> io::Result<()> {
+		let msg = CString::new(msg.replace('\0', "\\0")).unwrap();
+		unsafe {
+			libc::syslog(self.priority, b"%s\0".as_ptr() as _, msg.as_ptr())
+		};
+		Ok(())
+	}
+}
+
+/// Makes the sink a freshly opened file is written through.
+pub type OpenSink =
+	Box<dyn FnMut(File) -> io::Result<Box<dyn MessageSink>> + Send>;
+
+/// Writes to a new file in `dir`, named after the UTC time it was opened,
+/// every `interval`. The clock is only checked when a message is sent, so
+/// quiet periods don't leave empty files behind.
+pub struct Rotating {
+	dir: PathBuf,
+	extension: String,
+	interval: Duration,
+	open: OpenSink,
+	current: Option<(Instant, Box<dyn MessageSink>)>,
+}
+
+impl Rotating {
+	pub fn new(
+		dir: PathBuf,
+		extension: String,
+		interval: Duration,
+		open: OpenSink,
+	) -> Self {
+		Rotating {
+			dir,
+			extension,
+			interval,
+			open,
+			current: None,
+		}
+	}
+
+	fn rotate(&mut self) -> io::Result<()> {
+		if let Some((_, mut old)) = self.current.take() {
+			old.finish()?;
+		}
+
+		let name =
+			format!("{}.{}", timestamp(SystemTime::now()), self.extension);
+		let file = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(self.dir.join(name))?;
+		self.current = Some((Instant::now(), (self.open)(file)?));
+		Ok(())
+	}
+}
+
+impl MessageSink for Rotating {
+	fn send(&mut self, msg: &str) -> io::Result<()> {
+		match &self.current {
+			Some((opened, _)) if opened.elapsed() < self.interval => {}
+			_ => self.rotate()?,
+		}
+		self.current.as_mut().unwrap().1.send(msg)
+	}
+
+	fn finish(&mut self) -> io::Result<()> {
+		match self.current.take() {
+			Some((_, mut sink)) => sink.finish(),
+			None => Ok(()),
+		}
+	}
+}
+
+/// `t` as e.g. `20210704T120000Z`, which sorts chronologically.
+fn timestamp(t: SystemTime) -> String {
+	let tm = broken_down(t, libc::gmtime_r);
+
+	format!(
+		"{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+		tm.tm_year + 1900,
+		tm.tm_mon + 1,
+		tm.tm_mday,
+		tm.tm_hour,
+		tm.tm_min,
+		tm.tm_sec
+	)
+}
+
+fn broken_down(
+	t: SystemTime,
+	convert: unsafe extern "C" fn(
+		*const libc::time_t,
+		*mut libc::tm,
+	) -> *mut libc::tm,
+) -> libc::tm {
+	let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+	let mut tm = MaybeUninit::<libc::tm>::uninit();
+	unsafe {
+		convert(&(secs as libc::time_t), tm.as_mut_ptr());
+		tm.assume_init()
+	}
+}
+
+/// Names of the flags set in an `epoll_event.events` mask.
+fn event_names(events: u32) -> impl Iterator<Item = &'static str> {
+	const NAMES: &[(libc::c_int, &str)] = &[
+		(libc::EPOLLIN, "EPOLLIN"),
+		(libc::EPOLLPRI, "EPOLLPRI"),
+		(libc::EPOLLOUT, "EPOLLOUT"),
+		(libc::EPOLLERR, "EPOLLERR"),
+		(libc::EPOLLHUP, "EPOLLHUP"),
+		(libc::EPOLLRDHUP, "EPOLLRDHUP"),
+	];
+	NAMES
+		.iter()
+		.filter(move |(flag, _)| events & *flag as u32 != 0)
+		.map(|(_, name)| *name)
+}
+
+/// How a `Formatted` sink writes messages out.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Format {
+	/// The message as is, after its source if there's more than one pipe.
+	#[default]
+	Plain,
+	/// One JSON object per line with the `message`, its `source` and `tag`,
+	/// its `checksum` and, with `--debug-events`, the names of its epoll
+	/// `events`.
+	Json,
+	/// For terminals: the local time and source highlighted with ANSI
+	/// escapes.
+	Color,
+	/// `{message}`, `{source}`, `{tag}` and `{checksum}` replaced by the
+	/// message, the pipe it came from, that pipe's tag (or else the pipe
+	/// again) and its CRC-32 in hex, all but the message possibly empty.
+	Template(String),
+}
+
+/// Formats messages for the inner sink and leaves out the ones that don't
+/// contain `grep`. Anything that isn't a received message, like an EOF
+/// marker, is formatted too but never filtered.
+pub struct Formatted {
+	pub format: Format,
+	pub grep: Option<String>,
+	pub inner: Box<dyn MessageSink>,
+}
+
+impl Formatted {
+	fn format(&self, msg: &Received<'_>) -> String {
+		let checksum = msg.checksum.map(|crc| format!("{:08x}", crc));
+		let shown = msg.tag.or(msg.source);
+		match (&self.format, shown) {
+			(Format::Plain, Some(source)) => {
+				format!("{}: {}", source, msg.content)
+			}
+			(Format::Plain, None) => msg.content.to_string(),
+			(Format::Json, _) => {
+				let mut out = String::from("{");
+				if let Some(source) = msg.source {
+					out += &format!("\"source\":{},", json_string(source));
+				}
+				if let Some(tag) = msg.tag {
+					out += &format!("\"tag\":{},", json_string(tag));
+				}
+				out += &format!("\"message\":{}", json_string(msg.content));
+				if let Some(checksum) = &checksum {
+					out += &format!(",\"checksum\":\"{}\"", checksum);
+				}
+				if let Some(events) = msg.events {
+					let names: Vec<_> = event_names(events)
+						.map(|name| format!("\"{}\"", name))
+						.collect();
+					out += &format!(",\"events\":[{}]", names.join(","));
+				}
+				out + "}"
+			}
+			(Format::Color, source) => {
+				let tm = broken_down(SystemTime::now(), libc::localtime_r);
+				let time = format!(
+					"\x1b[2m{:02}:{:02}:{:02}\x1b[0m",
+					tm.tm_hour, tm.tm_min, tm.tm_sec
+				);
+				match source {
+					Some(source) => format!(
+						"{} \x1b[1;36m{}\x1b[0m {}",
+						time, source, msg.content
+					),
+					None => format!("{} {}", time, msg.content),
+				}
+			}
+			(Format::Template(template), shown) => {
+				expand(template, |name| match name {
+					"message" => Some(msg.content),
+					"source" => Some(msg.source.unwrap_or_default()),
+					"tag" => Some(shown.unwrap_or_default()),
+					"checksum" => Some(checksum.as_deref().unwrap_or_default()),
+					_ => None,
+				})
+			}
+		}
+	}
+}
+
+/// Replaces each `{name}` in `template` that `lookup` knows about.
+fn expand<'a>(
+	template: &str,
+	lookup: impl Fn(&str) -> Option<&'a str>,
+) -> String {
+	let mut out = String::with_capacity(template.len());
+	let mut rest = template;
+	while let Some(start) = rest.find('{') {
+		out.push_str(&rest[..start]);
+		rest = &rest[start..];
+
+		let value = rest
+			.find('}')
+			.and_then(|end| lookup(&rest[1..end]).map(|value| (value, end)));
+		match value {
+			Some((value, end)) => {
+				out.push_str(value);
+				rest = &rest[end + 1..];
+			}
+			None => {
+				out.push('{');
+				rest = &rest[1..];
+			}
+		}
+	}
+	out.push_str(rest);
+	out
+}
+
+impl MessageSink for Formatted {
+	fn send(&mut self, msg: &str) -> io::Result<()> {
+		let line = self.format(&Received::other(msg));
+		self.inner.send(&line)
+	}
+
+	fn emit(&mut self, msg: &Received<'_>) -> io::Result<()> {
+		if self
+			.grep
+			.as_ref()
+			.is_some_and(|grep| !msg.content.contains(grep.as_str()))
+		{
+			return Ok(());
+		}
+
+		let line = self.format(msg);
+		self.inner.send(&line)
+	}
+
+	fn finish(&mut self) -> io::Result<()> {
+		self.inner.finish()
+	}
+}
+
+/// Pipes each message through an external shell command whose stdout
+/// replaces it.
+pub enum Exec {
+	/// Runs the command once per message, with the message as its whole
+	/// stdin.
+	PerMessage(String),
+	/// Keeps one instance of the command running, writing each message to it
+	/// as a line and reading one line back. This only works with commands
+	/// that answer every input line with exactly one output line and don't
+	/// buffer their output, e.g. `jq -c --unbuffered`.
+	Persistent {
+		cmd: String,
+		child: Child,
+		stdout: BufReader<ChildStdout>,
+	},
+}
+
+fn shell(cmd: &str) -> Command {
+	let mut command = Command::new("sh");
+	command
+		.arg("-c")
+		.arg(cmd)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped());
+	command
+}
+
+impl Exec {
+	pub fn new(cmd: String, persistent: bool) -> io::Result<Self> {
+		if !persistent {
+			return Ok(Exec::PerMessage(cmd));
+		}
+
+		let mut child = shell(&cmd).spawn()?;
+		let stdout = BufReader::new(child.stdout.take().unwrap());
+
+		Ok(Exec::Persistent { cmd, child, stdout })
+	}
+
+	/// Runs `msg` through the command, returning its output without the
+	/// trailing newline.
+	pub fn transform(&mut self, msg: &str) -> io::Result<String> {
+		let mut out = String::new();
+
+		match self {
+			Exec::PerMessage(cmd) => {
+				let mut child = shell(cmd).spawn()?;
+				let mut stdin = child.stdin.take().unwrap();
+				let mut stdout = child.stdout.take().unwrap();
+				// Feeding stdin from a thread of its own keeps a command that
+				// writes before it has read everything from filling its
+				// stdout and blocking on us while we block on its stdin, like
+				// `Child::wait_with_output`. Dropping stdin once written is
+				// what tells the command the message is over. A command that
+				// exits without reading it all is fine as long as it exits
+				// successfully.
+				let written = thread::scope(|scope| {
+					let writer = scope.spawn(move || {
+						match stdin.write_all(msg.as_bytes()) {
+							Err(e) if e.kind() != io::ErrorKind::BrokenPipe => {
+								Err(e)
+							}
+							_ => Ok(()),
+						}
+					});
+					let read = stdout.read_to_string(&mut out);
+					writer.join().unwrap().and(read)
+				});
+				if let Err(e) = written {
+					let _ = child.wait();
+					return Err(e);
+				}
+
+				let status = child.wait()?;
+				if !status.success() {
+					return Err(io::Error::other(format!(
+						"`{}` exited with {}",
+						cmd, status
+					)));
+				}
+			}
+			Exec::Persistent { cmd, child, stdout } => {
+				let stdin = child.stdin.as_mut().unwrap();
+				writeln!(stdin, "{}", msg.replace('\n', " "))
+					.and_then(|_| stdin.flush())?;
+				if stdout.read_line(&mut out)? == 0 {
+					return Err(io::Error::new(
+						io::ErrorKind::UnexpectedEof,
+						format!("`{}` exited", cmd),
+					));
+				}
+			}
+		}
+
+		if out.ends_with('\n') {
+			out.pop();
+		}
+		Ok(out)
+	}
+}
+
+impl Drop for Exec {
+	fn drop(&mut self) {
+		// Closing stdin lets the command finish on its own, like a pipeline
+		// would.
+		if let Exec::Persistent { child, .. } = self {
+			drop(child.stdin.take());
+			let _ = child.wait();
+		}
+	}
+}
+
+/// Messages collected to be written out as one, either as a JSON array of
+/// strings or joined with `separator`.
+pub struct Batch {
+	size: usize,
+	timeout: Option<Duration>,
+	separator: Option<String>,
+	items: Vec<String>,
+	started: Option<Instant>,
+}
+
+impl Batch {
+	pub fn new(
+		size: usize,
+		timeout: Option<Duration>,
+		separator: Option<String>,
+	) -> Self {
+		Batch {
+			size,
+			timeout,
+			separator,
+			items: Vec::with_capacity(size),
+			started: None,
+		}
+	}
+
+	pub fn push(&mut self, item: String) {
+		self.started.get_or_insert_with(Instant::now);
+		self.items.push(item);
+	}
+
+	pub fn len(&self) -> usize {
+		self.items.len()
+	}
+
+	pub fn is_full(&self) -> bool {
+		self.items.len() >= self.size
+	}
+
+	/// When a partial batch is due to be written out anyway.
+	pub fn deadline(&self) -> Option<Instant> {
+		Some(self.started? + self.timeout?)
+	}
+
+	/// Empties the batch into the line to write out, if there's anything in
+	/// it.
+	pub fn take(&mut self) -> Option<String> {
+		if self.items.is_empty() {
+			return None;
+		}
+		self.started = None;
+
+		let items = mem::take(&mut self.items);
+		Some(match &self.separator {
+			Some(separator) => items.join(separator),
+			None => {
+				let items: Vec<_> =
+					items.iter().map(|item| json_string(item)).collect();
+				format!("[{}]", items.join(","))
+			}
+		})
+	}
+}
+
+/// What `Tee` does when one of its sinks fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OnSinkError {
+	/// Fail the whole `Tee`.
+	#[default]
+	Abort,
+	/// Report the error, stop using that sink and keep writing to the rest.
+	Continue,
+}
+
+/// Sends every message to several sinks, each with its own error policy.
+#[derive(Default)]
+pub struct Tee {
+	sinks: Vec<TeeSink>,
+	/// Errors of sinks that were dropped, see `take_failures()`.
+	failures: Vec<String>,
+}
+
+struct TeeSink {
+	name: String,
+	sink: Box<dyn MessageSink>,
+	on_error: OnSinkError,
+	failed: bool,
+}
+
+impl Tee {
+	pub fn push(
+		&mut self,
+		name: String,
+		sink: Box<dyn MessageSink>,
+		on_error: OnSinkError,
+	) {
+		self.sinks.push(TeeSink {
+			name,
+			sink,
+			on_error,
+			failed: false,
+		});
+	}
+
+	/// Errors of sinks that failed with `OnSinkError::Continue` since the
+	/// last call.
+	pub fn take_failures(&mut self) -> Vec<String> {
+		mem::take(&mut self.failures)
+	}
+
+	fn each(
+		&mut self,
+		mut f: impl FnMut(&mut dyn MessageSink) -> io::Result<()>,
+	) -> io::Result<()> {
+		for sink in self.sinks.iter_mut().filter(|s| !s.failed) {
+			if let Err(e) = f(sink.sink.as_mut()) {
+				let e =
+					io::Error::new(e.kind(), format!("{}: {}", sink.name, e));
+				if sink.on_error == OnSinkError::Abort {
+					return Err(e);
+				}
+
+				eprintln!("{}, no longer writing to it", e);
+				self.failures.push(e.to_string());
+				sink.failed = true;
+			}
+		}
+
+		if self.sinks.iter().all(|s| s.failed) {
+			return Err(io::Error::new(
+				io::ErrorKind::BrokenPipe,
+				"every output has failed",
+			));
+		}
+		Ok(())
+	}
+}
+
+impl MessageSink for Tee {
+	fn send(&mut self, msg: &str) -> io::Result<()> {
+		self.each(|sink| sink.send(msg))
+	}
+
+	fn emit(&mut self, msg: &Received<'_>) -> io::Result<()> {
+		self.each(|sink| sink.emit(msg))
+	}
+
+	fn finish(&mut self) -> io::Result<()> {
+		self.each(|sink| sink.finish())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn expand_known_placeholders() {
+		let lookup = |name: &str| match name {
+			"message" => Some("hi"),
+			"source" => Some(""),
+			_ => None,
+		};
+		assert_eq!(expand("[{source}] {message}!", lookup), "[] hi!");
+		assert_eq!(expand("{message}{message}", lookup), "hihi");
+	}
+
+	#[test]
+	fn expand_leaves_the_rest_alone() {
+		let lookup = |name: &str| (name == "message").then_some("hi");
+		assert_eq!(expand("{other} {message}", lookup), "{other} hi");
+		assert_eq!(expand("{{message}}", lookup), "{hi}");
+		assert_eq!(expand("{message", lookup), "{message");
+		assert_eq!(expand("}{", lookup), "}{");
+		assert_eq!(expand("", lookup), "");
+	}
+
+	#[test]
+	fn batch_as_json_array() {
+		let mut batch = Batch::new(2, None, None);
+		assert_eq!(batch.take(), None);
+
+		batch.push("a".to_string());
+		assert!(!batch.is_full());
+		batch.push("say \"b\"".to_string());
+		assert!(batch.is_full());
+		assert_eq!(batch.take().as_deref(), Some(r#"["a","say \"b\""]"#));
+		assert_eq!(batch.len(), 0);
+		assert_eq!(batch.take(), None);
+	}
+
+	#[test]
+	fn batch_with_separator_and_timeout() {
+		let timeout = Duration::from_secs(5);
+		let mut batch = Batch::new(3, Some(timeout), Some("\n".to_string()));
+		assert_eq!(batch.deadline(), None);
+
+		batch.push("a".to_string());
+		let deadline = batch.deadline().unwrap();
+		batch.push("b".to_string());
+		assert_eq!(batch.deadline(), Some(deadline));
+		assert_eq!(batch.take().as_deref(), Some("a\nb"));
+		assert_eq!(batch.deadline(), None);
+	}
+}
@@ -0,0 +1,139 @@
+/*
+ * pipe-poll
+ * Copyright (C) 2021 Safin Singh
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{
+	collections::VecDeque,
+	time::{Duration, Instant},
+};
+
+/// How often `--show-rate` reports.
+const REPORT_EVERY: Duration = Duration::from_secs(1);
+
+/// Turns running message and byte totals into per second rates over a
+/// sliding window, for `--show-rate`.
+pub struct RateMeter {
+	window: Duration,
+	/// `(when, messages, bytes)`, oldest first, reaching back at least
+	/// `window` once there's been enough time.
+	samples: VecDeque<(Instant, usize, usize)>,
+	next_report: Instant,
+}
+
+impl RateMeter {
+	pub fn new(window: Duration) -> Self {
+		let now = Instant::now();
+		RateMeter {
+			window,
+			samples: VecDeque::from(vec![(now, 0, 0)]),
+			next_report: now + REPORT_EVERY,
+		}
+	}
+
+	/// Records the current totals, returning a report line whenever one is
+	/// due.
+	pub fn tick(&mut self, totals: (usize, usize)) -> Option<String> {
+		self.tick_at(Instant::now(), totals)
+	}
+
+	fn tick_at(
+		&mut self,
+		now: Instant,
+		(messages, bytes): (usize, usize),
+	) -> Option<String> {
+		if now < self.next_report {
+			return None;
+		}
+		self.next_report = now + REPORT_EVERY;
+
+		self.samples.push_back((now, messages, bytes));
+		// Keep the newest sample that's at least a window old as the
+		// baseline.
+		while self
+			.samples
+			.get(1)
+			.is_some_and(|(when, ..)| now - *when >= self.window)
+		{
+			self.samples.pop_front();
+		}
+
+		let (since, old_messages, old_bytes) = self.samples[0];
+		let secs = (now - since).as_secs_f64();
+		Some(format!(
+			"rate: {:.1} msg/s, {}/s",
+			(messages - old_messages) as f64 / secs,
+			human_bytes((bytes - old_bytes) as f64 / secs)
+		))
+	}
+}
+
+fn human_bytes(mut n: f64) -> String {
+	for unit in &["B", "KiB", "MiB"] {
+		if n < 1024.0 {
+			return format!("{:.1} {}", n, unit);
+		}
+		n /= 1024.0;
+	}
+	format!("{:.1} GiB", n)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reports_once_a_second() {
+		let mut meter = RateMeter::new(Duration::from_secs(10));
+		let start = meter.samples[0].0;
+		assert_eq!(meter.tick_at(start, (5, 5)), None);
+
+		let at = |secs| start + Duration::from_secs(secs);
+		assert_eq!(
+			meter.tick_at(at(2), (20, 2048)).as_deref(),
+			Some("rate: 10.0 msg/s, 1.0 KiB/s")
+		);
+		assert_eq!(
+			meter.tick_at(at(2) + Duration::from_millis(500), (30, 0)),
+			None
+		);
+	}
+
+	#[test]
+	fn rate_covers_the_window() {
+		let mut meter = RateMeter::new(Duration::from_secs(2));
+		let start = meter.samples[0].0;
+		let at = |secs| start + Duration::from_secs(secs);
+		meter.tick_at(at(1), (10, 0));
+		meter.tick_at(at(2), (20, 0));
+		assert_eq!(
+			meter.tick_at(at(3), (50, 0)).as_deref(),
+			Some("rate: 20.0 msg/s, 0.0 B/s")
+		);
+		assert_eq!(meter.samples.len(), 3);
+	}
+
+	#[test]
+	fn human_units() {
+		assert_eq!(human_bytes(512.0), "512.0 B");
+		assert_eq!(human_bytes(1536.0), "1.5 KiB");
+		assert_eq!(human_bytes(3.0 * 1024.0 * 1024.0), "3.0 MiB");
+		assert_eq!(
+			human_bytes(2048.0 * 1024.0 * 1024.0 * 1024.0),
+			"2048.0 GiB"
+		);
+	}
+}
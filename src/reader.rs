@@ -0,0 +1,2316 @@
+/*
+ * pipe-poll
+ * Copyright (C) 2021 Safin Singh
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::{
+	crc32::crc32,
+	mmap::MappedFile,
+	secret::{self, Secret},
+	seqpacket, writers, Encoding, Framing, PipeError,
+};
+use libc::{
+	c_int, cpu_set_t, epoll_create, epoll_create1, epoll_ctl, epoll_event,
+	epoll_wait, ioctl, pipe2, sched_getaffinity, sched_setaffinity, CPU_ISSET,
+	CPU_SET, CPU_SETSIZE, EINTR, EPOLLET, EPOLLIN, EPOLLONESHOT, EPOLL_CTL_ADD,
+	EPOLL_CTL_DEL, EPOLL_CTL_MOD, FD_CLOEXEC, FIONREAD, F_SETFD, O_CLOEXEC,
+	O_NONBLOCK,
+};
+use std::{
+	cmp::{self, Reverse},
+	collections::VecDeque,
+	fmt,
+	fs::{File, OpenOptions},
+	io::{self, Read},
+	mem,
+	os::unix::{
+		fs::{FileTypeExt, OpenOptionsExt},
+		prelude::AsRawFd,
+	},
+	path::{Path, PathBuf},
+	sync::{
+		atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+		mpsc, Arc, Mutex, MutexGuard,
+	},
+	task::{Context, Poll, Waker},
+	thread::{self, JoinHandle},
+	time::{Duration, Instant, SystemTime},
+};
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+/// epoll tag of the self-pipe used to wake the epoll thread for shutdown.
+const WAKE: u64 = u64::MAX;
+/// Set in the epoll tag of a `Backend::SeqPacket` listening socket.
+const LISTENER: u64 = 1 << 62;
+/// Size of a single `read(2)`, the default capacity of a pipe.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// How often writers are counted while waiting for
+/// `ListenOptions::min_writers`.
+const WRITERS_POLL: c_int = 50;
+
+/// A single frame read from one of the watched pipes.
+#[derive(Clone, Debug)]
+pub struct Message {
+	/// Index of the pipe this was read from, i.e. its `epoll_event.u64` tag.
+	pub source: usize,
+	pub content: String,
+	/// Length of the frame as read, in bytes, before decoding and trimming;
+	/// what `PipeStats::bytes` and `PipeWatcher::totals()` count.
+	pub len: usize,
+	/// CRC-32 of the bytes read, before decoding and trimming but without
+	/// the trailer `ListenOptions::verify_checksum` takes off, i.e. the
+	/// value that trailer is checked against. `None` with
+	/// `ListenOptions::mmap_output`.
+	pub checksum: Option<u32>,
+	/// `epoll_event.events` of the wakeup whose read completed this
+	/// message, e.g. only `EPOLLHUP` for a session that's complete once the
+	/// writer has hung up.
+	pub events: u32,
+}
+
+impl Message {
+	/// Moves `content` into a `Secret`, without copying it.
+	pub fn into_secret(self) -> Secret {
+		self.content.into()
+	}
+}
+
+/// Snapshot of how the future/thread handoff has been behaving, see
+/// `PipeWatcher::stats()`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+	/// Times a consumer was polled and had to return `Poll::Pending`.
+	pub pending_polls: u64,
+	/// Pending polls which happened although nothing had woken the
+	/// consumer since its previous poll.
+	pub spurious_wakeups: u64,
+	pub lock_acquisitions: u64,
+	/// Total time spent waiting to acquire the state lock, by the consumers
+	/// and the epoll thread alike.
+	pub lock_wait: Duration,
+}
+
+/// What's been read from one of the pipes, see `PipeWatcher::pipe_stats()`.
+#[derive(Clone, Debug)]
+pub struct PipeStats {
+	pub path: PathBuf,
+	pub messages: usize,
+	pub bytes: usize,
+	/// As of the last read, see `PipeWatcher::is_writer_connected()`.
+	pub writer_connected: bool,
+	pub last_message: Option<SystemTime>,
+}
+
+/// A non-fatal problem the watcher ran into, see
+/// `PipeWatcher::recent_errors()`.
+#[derive(Clone, Debug)]
+pub struct LoggedError {
+	pub at: SystemTime,
+	pub message: String,
+}
+
+/// Default length of the log of recent errors.
+const ERROR_LOG_LEN: usize = 32;
+
+/// A piece of raw input as read, see `PipeWatcher::chunks()`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Chunk {
+	Data {
+		source: usize,
+		bytes: Vec<u8>,
+	},
+	/// Every writer of pipe `source` has hung up, ending the write session
+	/// (or the datagram) the preceding `Data` chunks belonged to.
+	End {
+		source: usize,
+	},
+}
+
+/// A consumer of `Chunk`s.
+struct ChunkSubscriber {
+	id: usize,
+	queue: VecDeque<Chunk>,
+	waker: Option<Waker>,
+}
+
+/// One consumer's share of the stream.
+struct Subscriber {
+	id: usize,
+	queue: VecDeque<Result<Message, PipeError>>,
+	waker: Option<Waker>,
+	/// Most messages to queue and what to do beyond that, unbounded when
+	/// unset.
+	bound: Option<(usize, SlowSubscriber)>,
+	/// Messages dropped since this consumer was last told about it.
+	missed: usize,
+	/// Whether this consumer has asked for a message yet.
+	polled: bool,
+}
+
+/// What a bounded subscription does when it's full, see
+/// `PipeWatcher::subscribe_bounded()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SlowSubscriber {
+	/// Drop the oldest queued message to make room, silently.
+	#[default]
+	DropOldest,
+	/// Drop the oldest too, but have the next `next()` resolve to
+	/// `PipeError::Missed` first.
+	Error,
+}
+
+/// Id of the consumer owned by the handle itself, the only one errors are
+/// delivered to.
+pub(crate) const PRIMARY: usize = 0;
+
+pub(crate) struct PipeWriteListenState {
+	/// Every consumer gets its own copy of each message in arrival order.
+	subscribers: Vec<Subscriber>,
+	chunk_subscribers: Vec<ChunkSubscriber>,
+	next_id: usize,
+	/// The last `replay_len` messages, handed to each new subscriber.
+	replay: VecDeque<Message>,
+	replay_len: usize,
+	/// See `ListenOptions::latest_only`.
+	latest_only: bool,
+	/// See `ListenOptions::secret`.
+	secret: bool,
+	/// Set once the epoll thread has exited.
+	closed: bool,
+	pub(crate) messages: usize,
+	pub(crate) bytes: usize,
+	/// Indexed by tag. `writer_connected` is kept in `Shared::writers`
+	/// instead so reads don't have to take the lock.
+	pub(crate) pipes: Vec<PipeStats>,
+	/// The last `error_log_len` non-fatal errors, oldest first.
+	errors: VecDeque<LoggedError>,
+	error_log_len: usize,
+}
+
+/// The state shared with the epoll thread, along with counters describing
+/// how it is being contended. The counters live outside the mutex so that
+/// recording them doesn't need it.
+pub(crate) struct Shared {
+	state: Mutex<PipeWriteListenState>,
+	pending_polls: AtomicU64,
+	spurious_wakeups: AtomicU64,
+	lock_acquisitions: AtomicU64,
+	lock_wait_ns: AtomicU64,
+	stopping: AtomicBool,
+	/// See `PipeWatcher::pause()`.
+	paused: AtomicBool,
+	/// Number of `chunks()` consumers.
+	chunk_subscribers: AtomicUsize,
+	/// Per pipe, whether a writer was connected as of the last read.
+	writers: Vec<AtomicBool>,
+	/// Pending `set_path()` calls, `None` once the epoll thread has exited.
+	path_changes: Mutex<Option<Vec<PathChange>>>,
+	/// Write end of the epoll thread's self-pipe.
+	wake_fd: c_int,
+	thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Shared {
+	/// Also recovers the state when the epoll thread panicked while holding
+	/// it, so that consumers still see it closed rather than panicking too.
+	pub(crate) fn lock(&self) -> MutexGuard<'_, PipeWriteListenState> {
+		let start = Instant::now();
+		let state = match self.state.lock() {
+			Ok(state) => state,
+			Err(poisoned) => poisoned.into_inner(),
+		};
+		let waited = start.elapsed().as_nanos() as u64;
+
+		self.lock_acquisitions.fetch_add(1, Ordering::Relaxed);
+		self.lock_wait_ns.fetch_add(waited, Ordering::Relaxed);
+		state
+	}
+
+	pub(crate) fn stats(&self) -> Stats {
+		Stats {
+			pending_polls: self.pending_polls.load(Ordering::Relaxed),
+			spurious_wakeups: self.spurious_wakeups.load(Ordering::Relaxed),
+			lock_acquisitions: self.lock_acquisitions.load(Ordering::Relaxed),
+			lock_wait: Duration::from_nanos(
+				self.lock_wait_ns.load(Ordering::Relaxed),
+			),
+		}
+	}
+
+	pub(crate) fn writer_connected(&self) -> bool {
+		self.writers.iter().any(|w| w.load(Ordering::Relaxed))
+	}
+
+	pub(crate) fn pipe_stats(&self) -> Vec<PipeStats> {
+		let mut pipes = self.lock().pipes.clone();
+		for (pipe, writer) in pipes.iter_mut().zip(&self.writers) {
+			pipe.writer_connected = writer.load(Ordering::Relaxed);
+		}
+		pipes
+	}
+
+	/// Stops the epoll thread and waits for it to close every pipe. Pending
+	/// and later reads resolve to `PipeError::Cancelled` once whatever was
+	/// already queued has been consumed.
+	pub(crate) fn shutdown(&self) {
+		if !self.stopping.swap(true, Ordering::SeqCst) {
+			self.wake();
+		}
+
+		if let Some(handle) = self.thread.lock().unwrap().take() {
+			let _ = handle.join();
+		}
+	}
+}
+
+impl Shared {
+	fn wake(&self) {
+		let byte = 1u8;
+		// The only way this fails is a full pipe, which means a wakeup is
+		// pending anyway.
+		unsafe { libc::write(self.wake_fd, &byte as *const u8 as _, 1) };
+	}
+
+	pub(crate) fn set_paused(&self, paused: bool) {
+		if self.paused.swap(paused, Ordering::SeqCst) != paused {
+			self.wake();
+		}
+	}
+
+	pub(crate) fn is_paused(&self) -> bool {
+		self.paused.load(Ordering::SeqCst)
+	}
+
+	/// Has the epoll thread swap pipe `tag` for `path`, and waits for it.
+	pub(crate) fn set_path(
+		&self,
+		tag: usize,
+		path: PathBuf,
+	) -> Result<(), PipeError> {
+		let (done, result) = mpsc::channel();
+		match self.path_changes.lock().unwrap().as_mut() {
+			Some(changes) => changes.push(PathChange { tag, path, done }),
+			None => return Err(PipeError::Cancelled),
+		}
+		self.wake();
+
+		result.recv().unwrap_or(Err(PipeError::Cancelled))
+	}
+}
+
+impl Drop for Shared {
+	fn drop(&mut self) {
+		unsafe { libc::close(self.wake_fd) };
+	}
+}
+
+impl PipeWriteListenState {
+	fn push(&mut self, item: Result<Message, PipeError>) {
+		if let Ok(msg) = &item {
+			if self.replay_len > 0 {
+				if self.replay.len() == self.replay_len {
+					let old = self.replay.pop_front();
+					self.wipe(old);
+				}
+				self.replay.push_back(msg.clone());
+			}
+
+			if self.latest_only {
+				let secret = self.secret;
+				for sub in &mut self.subscribers {
+					sub.queue.retain_mut(|queued| match queued {
+						Ok(old) if old.source == msg.source => {
+							if secret {
+								secret::wipe_str(&mut old.content);
+							}
+							false
+						}
+						_ => true,
+					});
+				}
+			}
+
+			let secret = self.secret;
+			for sub in self.subscribers.iter_mut().filter(|s| s.id != PRIMARY) {
+				if let Some((capacity, _)) = sub.bound {
+					while sub.queue.len() >= capacity {
+						if let Some(Ok(mut old)) = sub.queue.pop_front() {
+							if secret {
+								secret::wipe_str(&mut old.content);
+							}
+						}
+						sub.missed += 1;
+					}
+				}
+				sub.queue.push_back(Ok(msg.clone()));
+				if let Some(waker) = sub.waker.take() {
+					waker.wake()
+				}
+			}
+		}
+
+		// A watcher that's only read through its subscriptions would
+		// otherwise queue every message for itself, forever.
+		let shared = self.subscribers.len() > 1;
+		if let Some(sub) = self
+			.subscribers
+			.iter_mut()
+			.find(|s| s.id == PRIMARY && (s.polled || !shared))
+		{
+			sub.queue.push_back(item);
+			if let Some(waker) = sub.waker.take() {
+				waker.wake()
+			}
+		}
+	}
+
+	fn close(&mut self) {
+		self.closed = true;
+
+		for sub in &mut self.subscribers {
+			if let Some(waker) = sub.waker.take() {
+				waker.wake()
+			}
+		}
+		for sub in &mut self.chunk_subscribers {
+			if let Some(waker) = sub.waker.take() {
+				waker.wake()
+			}
+		}
+	}
+
+	fn push_chunk(&mut self, chunk: Chunk) {
+		for sub in &mut self.chunk_subscribers {
+			sub.queue.push_back(chunk.clone());
+			if let Some(waker) = sub.waker.take() {
+				waker.wake()
+			}
+		}
+	}
+
+	pub(crate) fn log_error(&mut self, message: String) {
+		if self.error_log_len == 0 {
+			return;
+		}
+		if self.errors.len() == self.error_log_len {
+			self.errors.pop_front();
+		}
+		self.errors.push_back(LoggedError {
+			at: SystemTime::now(),
+			message,
+		});
+	}
+
+	pub(crate) fn set_error_log_len(&mut self, len: usize) {
+		self.error_log_len = len;
+		while self.errors.len() > len {
+			self.errors.pop_front();
+		}
+	}
+
+	pub(crate) fn recent_errors(&self) -> Vec<LoggedError> {
+		self.errors.iter().cloned().collect()
+	}
+
+	pub(crate) fn set_replay_len(&mut self, len: usize) {
+		self.replay_len = len;
+		while self.replay.len() > len {
+			let old = self.replay.pop_front();
+			self.wipe(old);
+		}
+	}
+
+	/// Adds a consumer, primed with the replay buffer, and returns its id.
+	pub(crate) fn subscribe(
+		&mut self,
+		bound: Option<(usize, SlowSubscriber)>,
+	) -> usize {
+		let id = self.next_id;
+		self.next_id += 1;
+		let bound = bound.map(|(capacity, slow)| (capacity.max(1), slow));
+		if let Some(i) = self
+			.subscribers
+			.iter()
+			.position(|s| s.id == PRIMARY && !s.polled)
+		{
+			let unread = mem::take(&mut self.subscribers[i].queue);
+			for item in unread {
+				self.wipe(item.ok());
+			}
+		}
+		let replay = match bound {
+			Some((capacity, _)) => self.replay.len().saturating_sub(capacity),
+			None => 0,
+		};
+		self.subscribers.push(Subscriber {
+			id,
+			queue: self.replay.iter().skip(replay).cloned().map(Ok).collect(),
+			waker: None,
+			bound,
+			missed: 0,
+			polled: false,
+		});
+
+		id
+	}
+
+	pub(crate) fn unsubscribe(&mut self, id: usize) {
+		if let Some(i) = self.subscribers.iter().position(|s| s.id == id) {
+			let sub = self.subscribers.remove(i);
+			for item in sub.queue {
+				self.wipe(item.ok());
+			}
+		}
+	}
+
+	/// Zeroes a message that's being dropped with `ListenOptions::secret`.
+	fn wipe(&self, msg: Option<Message>) {
+		if let Some(mut msg) = msg.filter(|_| self.secret) {
+			secret::wipe_str(&mut msg.content);
+		}
+	}
+}
+
+impl Drop for PipeWriteListenState {
+	fn drop(&mut self) {
+		let queued =
+			self.subscribers.iter_mut().flat_map(|s| s.queue.drain(..));
+		let unread: Vec<_> = queued.filter_map(Result::ok).collect();
+		for msg in unread.into_iter().chain(mem::take(&mut self.replay)) {
+			self.wipe(Some(msg));
+		}
+	}
+}
+
+impl Shared {
+	pub(crate) fn subscribe_chunks(&self) -> usize {
+		let mut state = self.lock();
+		let id = state.next_id;
+		state.next_id += 1;
+		state.chunk_subscribers.push(ChunkSubscriber {
+			id,
+			queue: VecDeque::new(),
+			waker: None,
+		});
+
+		self.chunk_subscribers.fetch_add(1, Ordering::Relaxed);
+		id
+	}
+
+	pub(crate) fn unsubscribe_chunks(&self, id: usize) {
+		self.lock().chunk_subscribers.retain(|s| s.id != id);
+		self.chunk_subscribers.fetch_sub(1, Ordering::Relaxed);
+	}
+
+	/// Hands `chunk` to the chunk consumers, without taking the lock when
+	/// there are none.
+	fn push_chunk(&self, chunk: impl FnOnce() -> Chunk) {
+		if self.chunk_subscribers.load(Ordering::Relaxed) > 0 {
+			self.lock().push_chunk(chunk());
+		}
+	}
+}
+
+/// Tie-break rule for pipes reported by the same `epoll_wait` call. Messages
+/// from different wakeups are always delivered in the order they were read;
+/// this only decides the order within one batch.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MergeOrder {
+	/// Ascending `epoll_event.u64` tag, i.e. the order pipes were given in.
+	#[default]
+	Fd,
+	/// Descending `FIONREAD` byte count, ties broken by tag.
+	Bytes,
+	/// Ascending tag, rotated to start just after the pipe served last.
+	RoundRobin,
+}
+
+impl MergeOrder {
+	/// Sorts a batch of tags in place. `next` is the tag round-robin
+	/// ordering starts from.
+	fn sort(self, batch: &mut [usize], pipes: &[Pipe], next: usize) {
+		match self {
+			MergeOrder::Fd => batch.sort_unstable(),
+			// Cached so the backlog is read once per pipe, and the order
+			// comes from one snapshot of it.
+			MergeOrder::Bytes => batch.sort_by_cached_key(|&tag| {
+				let file = pipes[tag].file.as_ref();
+				let pending = file.and_then(|f| available_bytes(f).ok());
+				(Reverse(pending.unwrap_or(0)), tag)
+			}),
+			MergeOrder::RoundRobin => batch
+				.sort_by_key(|&tag| (tag + pipes.len() - next) % pipes.len()),
+		}
+	}
+}
+
+/// What the paths given to a listener or watcher are.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Backend {
+	/// Named pipes, created beforehand with `mkfifo`.
+	#[default]
+	Fifo,
+	/// Unix `SOCK_SEQPACKET` sockets which are bound and listened on at
+	/// each path. Every datagram is a message of its own, `Framing` doesn't
+	/// apply. Connections are served one at a time per path; the next one is
+	/// accepted once the current peer hangs up.
+	SeqPacket,
+}
+
+/// What to do with a message over `ListenOptions::max_bytes`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Oversize {
+	/// Stop with `PipeError::TooLarge`.
+	#[default]
+	Error,
+	/// Deliver the first `max_bytes` and drop the rest.
+	Truncate,
+	/// Drop the whole message and carry on.
+	Skip,
+}
+
+/// What to do when a pipe goes over `ListenOptions::max_lag`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Lag {
+	/// Log it and, when watching, hand the consumers a
+	/// `PipeError::Lagging` once each time the limit is crossed.
+	#[default]
+	Warn,
+	/// Stop with `PipeError::Lagging`.
+	Abort,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ListenOptions {
+	/// CPU core to pin the epoll thread to, see `allowed_cpus()`.
+	pub cpu: Option<usize>,
+	pub merge_order: MergeOrder,
+	/// Number of leading messages (e.g. a handshake) to read and discard
+	/// before anything is delivered. Skipped messages aren't counted.
+	pub skip: usize,
+	/// Stop reading once this many messages have been delivered, leaving
+	/// anything after them in the pipes.
+	pub max_messages: Option<usize>,
+	/// How long to wait for the first writer before giving up with
+	/// `PipeError::Timeout`. Waits forever when unset. A FIFO writer that
+	/// hasn't written anything yet is looked for in `/proc`, see
+	/// `min_writers`.
+	pub open_timeout: Option<Duration>,
+	/// `epoll_event.events` bitmask each pipe is registered with, `EPOLLIN`
+	/// when unset. `EPOLLHUP` and `EPOLLERR` are always reported.
+	pub events: Option<u32>,
+	pub backend: Backend,
+	pub framing: Framing,
+	pub encoding: Encoding,
+	/// Longest message accepted, in bytes; see `on_oversize`.
+	pub max_bytes: Option<usize>,
+	pub on_oversize: Oversize,
+	/// Append each message's bytes to this file through a memory mapping as
+	/// they're read, instead of collecting them in memory; messages then
+	/// arrive with an empty `content`. Only for a single FIFO with
+	/// `Framing::Session`.
+	pub mmap_output: Option<PathBuf>,
+	/// Read everything a pipe has buffered on its first wakeup, instead of
+	/// a single chunk, before settling into the normal loop.
+	pub drain_on_start: bool,
+	/// Only keep the newest message from each pipe that hasn't been taken
+	/// yet, for values where older ones are stale once a new one arrives.
+	/// Errors are never dropped.
+	pub latest_only: bool,
+	/// Every message ends with a big-endian CRC-32 of the rest, put there by
+	/// the producer; it's checked and taken off, and a message it doesn't
+	/// match is replaced by `PipeError::ChecksumMismatch`.
+	pub verify_checksum: bool,
+	/// The messages are secrets, e.g. passphrases: every buffer they pass
+	/// through is zeroed once it's done with, as is anything dropped
+	/// unread, so they don't linger in freed memory. Use
+	/// `Message::into_secret()` to keep that up on the receiving end.
+	/// `PipeWatcher::chunks()` copies aren't covered. Only for UTF-8
+	/// without `mmap_output`.
+	pub secret: bool,
+	/// Don't read anything until this many writers, across all the pipes,
+	/// have connected; a barrier for producers that should start together.
+	/// With `Backend::SeqPacket` that's connections accepted, one per path.
+	/// FIFOs don't keep count of their writers, so they're looked for in
+	/// `/proc`, which only finds other users' processes as root.
+	pub min_writers: usize,
+	/// Most bytes left waiting in a pipe (its `FIONREAD` count) after a
+	/// read before reading counts as falling behind; see `on_lag`.
+	pub max_lag: Option<usize>,
+	pub on_lag: Lag,
+}
+
+/// A request to watch `path` instead of pipe `tag`.
+pub(crate) struct PathChange {
+	tag: usize,
+	path: PathBuf,
+	done: mpsc::Sender<Result<(), PipeError>>,
+}
+
+/// A pipe waiting to be reopened after its writers went away.
+struct Reopen {
+	tag: usize,
+	at: Instant,
+	backoff: Duration,
+}
+
+/// One watched pipe as seen by the epoll thread.
+struct Pipe {
+	path: PathBuf,
+	/// `None` while waiting to be reopened, or with `Backend::SeqPacket`
+	/// while waiting for a connection.
+	file: Option<File>,
+	listener: Option<File>,
+	/// Bytes read but not framed yet.
+	buf: Vec<u8>,
+	/// Whether anything was read since the pipe was last opened.
+	seen_data: bool,
+	/// `epoll_event.events` of the wakeup being handled, see
+	/// `Message::events`.
+	events: u32,
+	/// Whether the pipe was over `ListenOptions::max_lag` as of the last
+	/// read.
+	lagging: bool,
+	/// Whether the `drain_on_start` pass has happened already.
+	drained: bool,
+	/// Whether the rest of the current frame is being dropped for going
+	/// over `ListenOptions::max_bytes`.
+	discarding: bool,
+}
+
+/// Everything owned by the epoll thread.
+struct Worker {
+	shared: Arc<Shared>,
+	opts: ListenOptions,
+	follow: bool,
+	epoll_fd: c_int,
+	/// Read end of the self-pipe.
+	wake_rx: c_int,
+	pipes: Vec<Pipe>,
+	reopens: Vec<Reopen>,
+	/// Tag round-robin merging starts from.
+	next: usize,
+	to_skip: usize,
+	/// Set by a one-shot listener once it has delivered something.
+	done: bool,
+	chunk: Vec<u8>,
+	/// `ListenOptions::mmap_output`, and where the current message starts
+	/// in it.
+	mapped: Option<(MappedFile, usize)>,
+}
+
+/// Opens every pipe in `paths` and spawns the epoll thread feeding the
+/// returned state. In `follow` mode the thread keeps reopening each pipe and
+/// reading every subsequent write; otherwise it stops after the first
+/// message.
+pub(crate) fn spawn<P: AsRef<Path>>(
+	paths: &[P],
+	opts: &ListenOptions,
+	follow: bool,
+) -> Result<Arc<Shared>, PipeError> {
+	let invalid = |e: &str| Err(PipeError::InvalidOptions(e.to_string()));
+	if opts.mmap_output.is_some()
+		&& (paths.len() != 1
+			|| opts.backend != Backend::Fifo
+			|| opts.framing != Framing::Session)
+	{
+		return invalid("mmap output needs a single FIFO with session framing");
+	}
+	if opts.secret
+		&& (opts.mmap_output.is_some() || opts.encoding != Encoding::Utf8)
+	{
+		return invalid("secrets need UTF-8 without mmap output");
+	}
+	if opts.encoding.is_wide()
+		&& matches!(opts.framing, Framing::Delimiter(_) | Framing::PerRead)
+	{
+		return invalid("UTF-16 needs session or length-prefixed framing");
+	}
+	if let Some(cpu) = opts.cpu {
+		let allowed = allowed_cpus().map_err(PipeError::EpollFailed)?;
+		if !allowed.contains(&cpu) {
+			return Err(PipeError::InvalidOptions(format!(
+				"CPU {} is not in this process's affinity mask",
+				cpu
+			)));
+		}
+	}
+
+	let mapped = match &opts.mmap_output {
+		Some(path) => {
+			let file = MappedFile::open(path).map_err(|e| {
+				PipeError::OpenFailed(io::Error::new(
+					e.kind(),
+					format!("{}: {}", path.display(), e),
+				))
+			})?;
+			let start = file.len();
+			Some((file, start))
+		}
+		None => None,
+	};
+
+	let mut wake = [0; 2];
+	check_errno!(pipe2(wake.as_mut_ptr(), O_CLOEXEC | O_NONBLOCK))
+		.map_err(PipeError::EpollFailed)?;
+	let [wake_rx, wake_fd] = wake;
+
+	let shared = Arc::new(Shared {
+		state: Mutex::new(PipeWriteListenState {
+			subscribers: Vec::new(),
+			chunk_subscribers: Vec::new(),
+			next_id: PRIMARY,
+			replay: VecDeque::new(),
+			replay_len: 0,
+			latest_only: opts.latest_only,
+			secret: opts.secret,
+			closed: false,
+			messages: 0,
+			bytes: 0,
+			pipes: paths
+				.iter()
+				.map(|path| PipeStats {
+					path: path.as_ref().to_path_buf(),
+					messages: 0,
+					bytes: 0,
+					writer_connected: false,
+					last_message: None,
+				})
+				.collect(),
+			errors: VecDeque::new(),
+			error_log_len: ERROR_LOG_LEN,
+		}),
+		pending_polls: AtomicU64::new(0),
+		spurious_wakeups: AtomicU64::new(0),
+		lock_acquisitions: AtomicU64::new(0),
+		lock_wait_ns: AtomicU64::new(0),
+		stopping: AtomicBool::new(false),
+		paused: AtomicBool::new(false),
+		chunk_subscribers: AtomicUsize::new(0),
+		writers: paths.iter().map(|_| AtomicBool::new(false)).collect(),
+		path_changes: Mutex::new(Some(Vec::new())),
+		wake_fd,
+		thread: Mutex::new(None),
+	});
+	shared.lock().subscribe(None);
+
+	let epoll_fd = match create_epoll() {
+		Ok(fd) => fd,
+		Err(e) => {
+			unsafe { libc::close(wake_rx) };
+			return Err(PipeError::EpollFailed(e));
+		}
+	};
+	let mut event = epoll_event {
+		events: EPOLLIN as u32,
+		u64: WAKE,
+	};
+	if let Err(e) =
+		check_errno!(epoll_ctl(epoll_fd, EPOLL_CTL_ADD, wake_rx, &mut event))
+	{
+		unsafe {
+			libc::close(epoll_fd);
+			libc::close(wake_rx);
+		}
+		return Err(PipeError::EpollFailed(e));
+	}
+
+	let pipes = match paths
+		.iter()
+		.enumerate()
+		.map(|(tag, path)| {
+			let path = path.as_ref().to_path_buf();
+			let (file, listener) = match opts.backend {
+				Backend::Fifo => (
+					Some(open_registered(epoll_fd, &path, tag, opts.events)?),
+					None,
+				),
+				Backend::SeqPacket => {
+					(None, Some(listen_registered(epoll_fd, &path, tag)?))
+				}
+			};
+
+			Ok(Pipe {
+				path,
+				file,
+				listener,
+				buf: Vec::new(),
+				seen_data: false,
+				events: 0,
+				lagging: false,
+				drained: false,
+				discarding: false,
+			})
+		})
+		.collect::<Result<Vec<_>, _>>()
+	{
+		Ok(pipes) => pipes,
+		Err(e) => {
+			unsafe {
+				libc::close(epoll_fd);
+				libc::close(wake_rx);
+			}
+			return Err(e);
+		}
+	};
+
+	let worker = Worker {
+		shared: shared.clone(),
+		opts: opts.clone(),
+		follow,
+		epoll_fd,
+		wake_rx,
+		pipes,
+		reopens: Vec::new(),
+		next: 0,
+		to_skip: opts.skip,
+		done: false,
+		chunk: vec![0; CHUNK_SIZE],
+		mapped,
+	};
+	let handle = thread::spawn(move || {
+		let mut exit = WorkerExit {
+			shared: worker.shared.clone(),
+			epoll_fd: worker.epoll_fd,
+			wake_rx,
+			fatal: None,
+		};
+		// Dropping the worker closes every pipe.
+		exit.fatal = worker.run().err();
+	});
+	*shared.thread.lock().unwrap() = Some(handle);
+
+	Ok(shared)
+}
+
+/// Cleans up after the epoll thread however it ends, a panic included, so
+/// that consumers are always told it has stopped instead of waiting forever.
+struct WorkerExit {
+	shared: Arc<Shared>,
+	epoll_fd: c_int,
+	wake_rx: c_int,
+	fatal: Option<PipeError>,
+}
+
+impl Drop for WorkerExit {
+	fn drop(&mut self) {
+		// Fails whatever `set_path()` calls are still waiting.
+		let mut changes = match self.shared.path_changes.lock() {
+			Ok(changes) => changes,
+			Err(poisoned) => poisoned.into_inner(),
+		};
+		changes.take();
+		drop(changes);
+		unsafe {
+			libc::close(self.epoll_fd);
+			libc::close(self.wake_rx);
+		}
+
+		let mut state = self.shared.lock();
+		if thread::panicking() {
+			self.fatal = Some(PipeError::Panicked);
+		}
+		if let Some(e) = self.fatal.take() {
+			state.push(Err(e));
+		}
+		state.close();
+	}
+}
+
+impl Worker {
+	/// Waits on and reads from the pipes until a fatal error, a shutdown
+	/// request, or until a one-shot listener is done.
+	fn run(mut self) -> Result<(), PipeError> {
+		if let Some(cpu) = self.opts.cpu {
+			pin_to_cpu(cpu).map_err(PipeError::EpollFailed)?;
+		}
+
+		let mut events =
+			vec![epoll_event { events: 0, u64: 0 }; self.pipes.len() + 1];
+		let mut connect_by = self.opts.open_timeout.map(|t| Instant::now() + t);
+		if self.opts.min_writers > 0 && !self.wait_writers(connect_by)? {
+			return Ok(());
+		}
+
+		loop {
+			if self.shared.is_paused() {
+				self.wait_paused()?;
+				if self.shared.stopping.load(Ordering::SeqCst) {
+					return Ok(());
+				}
+				continue;
+			}
+
+			let deadline =
+				self.reopens.iter().map(|r| r.at).chain(connect_by).min();
+
+			let ready = match check_errno!(epoll_wait(
+				self.epoll_fd,
+				events.as_mut_ptr(),
+				events.len() as c_int,
+				epoll_timeout(deadline),
+			)) {
+				Ok(ready) => ready as usize,
+				Err(e) if e.raw_os_error() == Some(EINTR) => {
+					self.shared.lock().log_error(format!("epoll_wait: {}", e));
+					continue;
+				}
+				Err(e) => return Err(PipeError::EpollFailed(e)),
+			};
+			if self.shared.stopping.load(Ordering::SeqCst) {
+				return Ok(());
+			}
+
+			// Being woken up for a pause or `set_path()` says nothing about
+			// the writers.
+			let now = Instant::now();
+			if events[..ready].iter().any(|ev| ev.u64 != WAKE) {
+				connect_by = None;
+			} else if connect_by.is_some_and(|at| at <= now) {
+				if !self.has_silent_writer() {
+					return Err(PipeError::Timeout);
+				}
+				connect_by = None;
+			}
+
+			self.retry_reopens(now);
+
+			let mut woken = false;
+			let mut batch = Vec::with_capacity(ready);
+			for (tag, flags) in
+				events[..ready].iter().map(|ev| (ev.u64, ev.events))
+			{
+				match tag {
+					WAKE => woken = true,
+					tag if tag & LISTENER != 0 => {
+						self.accept((tag & !LISTENER) as usize)?
+					}
+					tag => {
+						self.pipes[tag as usize].events = flags;
+						batch.push(tag as usize)
+					}
+				}
+			}
+			self.opts
+				.merge_order
+				.sort(&mut batch, &self.pipes, self.next);
+
+			for tag in batch {
+				self.read_ready(tag)?;
+				if self.done {
+					return Ok(());
+				}
+			}
+
+			// Only once the batch is done, so nothing left in it refers to
+			// a pipe that has been swapped out.
+			if woken {
+				self.change_paths();
+			}
+		}
+	}
+
+	/// Sleeps on the self-pipe alone while paused, leaving whatever is
+	/// written to the pipes in their kernel buffers, which blocks writers
+	/// once they're full. `set_path()` still works meanwhile.
+	fn wait_paused(&mut self) -> Result<(), PipeError> {
+		let mut fd = libc::pollfd {
+			fd: self.wake_rx,
+			events: libc::POLLIN,
+			revents: 0,
+		};
+		match check_errno!(libc::poll(&mut fd, 1, -1)) {
+			Ok(_) => self.change_paths(),
+			Err(e) if e.raw_os_error() == Some(EINTR) => {}
+			Err(e) => return Err(PipeError::EpollFailed(e)),
+		}
+		Ok(())
+	}
+
+	/// Whether a FIFO has a writer which epoll can't tell about because it
+	/// has opened the pipe without writing anything.
+	fn has_silent_writer(&self) -> bool {
+		self.opts.backend == Backend::Fifo
+			&& self.pipes.iter().any(|p| writers::count(&p.path) > 0)
+	}
+
+	/// Holds off reading until `ListenOptions::min_writers` writers are
+	/// connected, taking connections meanwhile with `Backend::SeqPacket`.
+	/// Returns false when stopped first, and `PipeError::Timeout` once
+	/// `deadline` passes.
+	fn wait_writers(
+		&mut self,
+		deadline: Option<Instant>,
+	) -> Result<bool, PipeError> {
+		loop {
+			if self.shared.stopping.load(Ordering::SeqCst) {
+				return Ok(false);
+			}
+			let connected = match self.opts.backend {
+				Backend::Fifo => {
+					self.pipes.iter().map(|p| writers::count(&p.path)).sum()
+				}
+				Backend::SeqPacket => {
+					self.pipes.iter().filter(|p| p.file.is_some()).count()
+				}
+			};
+			if connected >= self.opts.min_writers {
+				return Ok(true);
+			}
+			if deadline.is_some_and(|at| at <= Instant::now()) {
+				return Err(PipeError::Timeout);
+			}
+
+			let pollfd = |fd| libc::pollfd {
+				fd,
+				events: libc::POLLIN,
+				revents: 0,
+			};
+			let timeout = match epoll_timeout(deadline) {
+				-1 => WRITERS_POLL,
+				ms => ms.min(WRITERS_POLL),
+			};
+			let mut fds = vec![pollfd(self.wake_rx)];
+			let mut listening = Vec::new();
+			for (tag, pipe) in self.pipes.iter().enumerate() {
+				if let (None, Some(listener)) = (&pipe.file, &pipe.listener) {
+					fds.push(pollfd(listener.as_raw_fd()));
+					listening.push(tag);
+				}
+			}
+			match check_errno!(libc::poll(
+				fds.as_mut_ptr(),
+				fds.len() as _,
+				timeout
+			)) {
+				Ok(_) => {}
+				Err(e) if e.raw_os_error() == Some(EINTR) => continue,
+				Err(e) => return Err(PipeError::EpollFailed(e)),
+			}
+
+			if fds[0].revents != 0 {
+				self.change_paths();
+			}
+			for (fd, tag) in fds[1..].iter().zip(listening) {
+				if fd.revents != 0 {
+					self.accept(tag)?;
+				}
+			}
+		}
+	}
+
+	/// Empties the self-pipe and applies the `set_path()` calls made since
+	/// the last wakeup.
+	fn change_paths(&mut self) {
+		let mut buf = [0u8; 64];
+		while check_errno!(libc::read(
+			self.wake_rx,
+			buf.as_mut_ptr() as _,
+			buf.len()
+		))
+		.is_ok_and(|n| n > 0)
+		{}
+
+		let changes = match self.shared.path_changes.lock().unwrap().as_mut() {
+			Some(changes) => mem::take(changes),
+			None => return,
+		};
+		for change in changes {
+			let result = self.change_path(change.tag, change.path);
+			let _ = change.done.send(result);
+		}
+	}
+
+	/// Starts watching `path` as pipe `tag`, dropping the old one only once
+	/// the new one has been opened.
+	fn change_path(
+		&mut self,
+		tag: usize,
+		path: PathBuf,
+	) -> Result<(), PipeError> {
+		if tag >= self.pipes.len() {
+			return Err(PipeError::OpenFailed(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				format!("there's no pipe {}", tag),
+			)));
+		}
+
+		let (file, listener) = match self.opts.backend {
+			Backend::Fifo => (
+				Some(open_registered(
+					self.epoll_fd,
+					&path,
+					tag,
+					self.opts.events,
+				)?),
+				None,
+			),
+			Backend::SeqPacket => {
+				(None, Some(listen_registered(self.epoll_fd, &path, tag)?))
+			}
+		};
+
+		let old = mem::replace(
+			&mut self.pipes[tag],
+			Pipe {
+				path,
+				file,
+				listener,
+				buf: Vec::new(),
+				seen_data: false,
+				events: 0,
+				lagging: false,
+				drained: false,
+				discarding: false,
+			},
+		);
+		for fd in old.file.iter().chain(&old.listener).map(|f| f.as_raw_fd()) {
+			// A listener isn't registered while it has a connection.
+			let _ = check_errno!(epoll_ctl(
+				self.epoll_fd,
+				EPOLL_CTL_DEL,
+				fd,
+				std::ptr::null_mut()
+			));
+		}
+		self.reopens.retain(|r| r.tag != tag);
+
+		self.shared.writers[tag].store(false, Ordering::Relaxed);
+		self.shared.lock().pipes[tag].path = self.pipes[tag].path.clone();
+		Ok(())
+	}
+
+	/// Reads what's available on pipe `tag` and delivers every complete
+	/// frame. See `should_drain` for how much is read.
+	fn read_ready(&mut self, tag: usize) -> Result<(), PipeError> {
+		if self.opts.backend == Backend::SeqPacket {
+			return self.read_datagrams(tag);
+		}
+
+		let drain = self.should_drain(tag);
+
+		let mut eof = false;
+		let mut failed = None;
+		loop {
+			let file = match &mut self.pipes[tag].file {
+				Some(file) => file,
+				None => return Ok(()),
+			};
+
+			match file.read(&mut self.chunk) {
+				Ok(0) => {
+					eof = true;
+					break;
+				}
+				Ok(n) => {
+					self.shared.push_chunk(|| Chunk::Data {
+						source: tag,
+						bytes: self.chunk[..n].to_vec(),
+					});
+					let res = self.take_frames(tag, n, false);
+					if self.opts.secret {
+						secret::zero(&mut self.chunk[..n]);
+					}
+					res?;
+					if self.done {
+						return Ok(());
+					}
+					self.check_lag(tag)?;
+					if !drain {
+						break;
+					}
+				}
+				Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+				Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+					self.log_error(tag, e);
+					continue;
+				}
+				Err(e) => {
+					failed = Some(e);
+					break;
+				}
+			}
+		}
+
+		let seen_data = self.pipes[tag].seen_data;
+		// Data means someone's still writing or has just left, in which case
+		// EOF follows.
+		self.shared.writers[tag].store(seen_data && !eof, Ordering::Relaxed);
+		if eof {
+			if seen_data {
+				self.shared.push_chunk(|| Chunk::End { source: tag });
+			}
+			self.take_frames(tag, 0, true)?;
+			if self.done {
+				return Ok(());
+			}
+		}
+
+		if let Some(e) = failed {
+			self.deliver_err(PipeError::ReadFailed(e));
+			if self.done {
+				return Ok(());
+			}
+			eof = true;
+		}
+
+		self.finish_read(tag, eof, seen_data)
+	}
+
+	/// Adds the first `n` bytes of the read buffer to pipe `tag` and delivers
+	/// every frame that's complete, enforcing `ListenOptions::max_bytes` on
+	/// them as well as on the one still being read.
+	fn take_frames(
+		&mut self,
+		tag: usize,
+		n: usize,
+		eof: bool,
+	) -> Result<(), PipeError> {
+		if self.mapped.is_some() {
+			return self.take_mapped(tag, n, eof);
+		}
+
+		let framing = self.opts.framing;
+		let secret = self.opts.secret;
+		let pipe = &mut self.pipes[tag];
+		pipe.seen_data |= n > 0;
+		if secret {
+			secret::extend(&mut pipe.buf, &self.chunk[..n]);
+		} else {
+			pipe.buf.extend_from_slice(&self.chunk[..n]);
+		}
+
+		let (frames, oversized) = framing.split_limited(
+			&mut pipe.buf,
+			&mut pipe.discarding,
+			self.opts.max_bytes,
+			eof,
+		);
+		if secret {
+			// Every byte dropped, copied out or shifted down is now in the
+			// spare capacity.
+			secret::zero_spare(&mut pipe.buf);
+		}
+		for frame in frames {
+			self.deliver_limited(tag, frame)?;
+			if self.done {
+				return Ok(());
+			}
+		}
+
+		if eof && !self.pipes[tag].buf.is_empty() {
+			let mut partial = mem::take(&mut self.pipes[tag].buf);
+			self.log_error(
+				tag,
+				format!(
+					"dropped an incomplete frame of {} bytes",
+					partial.len()
+				),
+			);
+			self.discard(&mut partial);
+			return Ok(());
+		}
+
+		let (mut partial, max) = match (oversized, self.opts.max_bytes) {
+			(Some(partial), Some(max)) => (partial, max),
+			_ => return Ok(()),
+		};
+		if self.opts.on_oversize != Oversize::Error {
+			self.log_oversize(tag, max);
+		}
+		match self.opts.on_oversize {
+			Oversize::Error => {
+				self.discard(&mut partial);
+				Err(PipeError::TooLarge(max))
+			}
+			Oversize::Truncate => {
+				let frame = truncate(partial, max, self.opts.encoding);
+				self.deliver(tag, frame);
+				Ok(())
+			}
+			Oversize::Skip => {
+				self.discard(&mut partial);
+				Ok(())
+			}
+		}
+	}
+
+	/// `take_frames` for `ListenOptions::mmap_output`: the session being
+	/// read goes straight into the mapping and is rewound if it's skipped.
+	fn take_mapped(
+		&mut self,
+		tag: usize,
+		n: usize,
+		eof: bool,
+	) -> Result<(), PipeError> {
+		let len = self.mapped.as_ref().map_or(0, |(m, start)| m.len() - start);
+		match self.opts.max_bytes {
+			// Only once, when the limit is first crossed.
+			Some(max)
+				if len <= max
+					&& len + n > max
+					&& self.opts.on_oversize != Oversize::Error =>
+			{
+				self.log_oversize(tag, max)
+			}
+			_ => {}
+		}
+
+		let (mapped, start) = self.mapped.as_mut().unwrap();
+		let pipe = &mut self.pipes[tag];
+		pipe.seen_data |= n > 0;
+
+		let mut data = &self.chunk[..n];
+		match self.opts.max_bytes {
+			Some(max) if len + data.len() > max => {
+				match self.opts.on_oversize {
+					Oversize::Error => return Err(PipeError::TooLarge(max)),
+					Oversize::Truncate => data = &data[..max - len],
+					Oversize::Skip => {
+						mapped.truncate(*start);
+						pipe.discarding = true;
+					}
+				}
+			}
+			_ => {}
+		}
+		if !pipe.discarding {
+			mapped.extend(data).map_err(PipeError::WriteFailed)?;
+		}
+
+		if !eof {
+			return Ok(());
+		}
+		let len = mapped.len() - *start;
+		if mem::take(&mut pipe.discarding) || len == 0 {
+			return Ok(());
+		}
+
+		if self.to_skip > 0 {
+			self.to_skip -= 1;
+			mapped.truncate(*start);
+		} else {
+			*start += len;
+			self.push_message(tag, String::new(), None, len);
+		}
+		Ok(())
+	}
+
+	/// Compares what's still waiting in pipe `tag` against
+	/// `ListenOptions::max_lag`.
+	fn check_lag(&mut self, tag: usize) -> Result<(), PipeError> {
+		let (max, file) = match (self.opts.max_lag, &self.pipes[tag].file) {
+			(Some(max), Some(file)) => (max, file),
+			_ => return Ok(()),
+		};
+		let pending = available_bytes(file).map_err(PipeError::ReadFailed)?;
+		let lagging = pending > max;
+		let crossed = lagging && !self.pipes[tag].lagging;
+		self.pipes[tag].lagging = lagging;
+		if !crossed {
+			return Ok(());
+		}
+
+		let err = PipeError::Lagging { pending, max };
+		match self.opts.on_lag {
+			Lag::Abort => Err(err),
+			Lag::Warn if self.follow => {
+				self.log_error(tag, &err);
+				self.shared.lock().push(Err(err));
+				Ok(())
+			}
+			Lag::Warn => {
+				self.log_error(tag, err);
+				Ok(())
+			}
+		}
+	}
+
+	/// `deliver`s a complete frame, unless it's over
+	/// `ListenOptions::max_bytes` and the `Oversize` policy says otherwise.
+	fn deliver_limited(
+		&mut self,
+		tag: usize,
+		mut frame: Vec<u8>,
+	) -> Result<(), PipeError> {
+		match self.opts.max_bytes {
+			Some(max) if frame.len() > max => match self.opts.on_oversize {
+				Oversize::Error => return Err(PipeError::TooLarge(max)),
+				Oversize::Truncate => {
+					self.log_oversize(tag, max);
+					let frame = truncate(frame, max, self.opts.encoding);
+					self.deliver(tag, frame)
+				}
+				Oversize::Skip => {
+					self.log_oversize(tag, max);
+					self.discard(&mut frame);
+				}
+			},
+			_ => self.deliver(tag, frame),
+		}
+
+		Ok(())
+	}
+
+	/// Like `read_ready`, but receives whole datagrams from a
+	/// `Backend::SeqPacket` connection, each delivered as is.
+	fn read_datagrams(&mut self, tag: usize) -> Result<(), PipeError> {
+		let drain = self.should_drain(tag);
+
+		let mut eof = false;
+		loop {
+			let file = match &self.pipes[tag].file {
+				Some(file) => file,
+				None => return Ok(()),
+			};
+
+			match seqpacket::recv_datagram(
+				file,
+				&mut self.chunk,
+				self.opts.secret,
+			) {
+				Ok(0) => {
+					eof = true;
+					break;
+				}
+				Ok(n) => {
+					self.pipes[tag].seen_data = true;
+					self.shared.writers[tag].store(true, Ordering::Relaxed);
+					self.shared.push_chunk(|| Chunk::Data {
+						source: tag,
+						bytes: self.chunk[..n].to_vec(),
+					});
+					self.shared.push_chunk(|| Chunk::End { source: tag });
+
+					let res =
+						self.deliver_limited(tag, self.chunk[..n].to_vec());
+					if self.opts.secret {
+						secret::zero(&mut self.chunk[..n]);
+					}
+					res?;
+					if self.done {
+						return Ok(());
+					}
+					if !drain {
+						break;
+					}
+				}
+				Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+				Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+					self.log_error(tag, e);
+					continue;
+				}
+				Err(e) => {
+					self.deliver_err(PipeError::ReadFailed(e));
+					if self.done {
+						return Ok(());
+					}
+					eof = true;
+					break;
+				}
+			}
+		}
+
+		if eof {
+			self.shared.writers[tag].store(false, Ordering::Relaxed);
+		}
+		let seen_data = self.pipes[tag].seen_data;
+		self.finish_read(tag, eof, seen_data)
+	}
+
+	/// Normally a single read per wakeup, which keeps one busy pipe from
+	/// starving the others. A pipe's first wakeup with `drain_on_start`, and
+	/// every wakeup with `EPOLLET` (which won't fire again for data that's
+	/// already there), reads until `EAGAIN` instead.
+	fn should_drain(&mut self, tag: usize) -> bool {
+		let edge_triggered =
+			self.opts.events.unwrap_or(0) & EPOLLET as u32 != 0;
+		let pipe = &mut self.pipes[tag];
+		let drain =
+			edge_triggered || (self.opts.drain_on_start && !pipe.drained);
+
+		pipe.drained = true;
+		drain
+	}
+
+	fn finish_read(
+		&mut self,
+		tag: usize,
+		eof: bool,
+		seen_data: bool,
+	) -> Result<(), PipeError> {
+		if !eof {
+			self.rearm(tag)?;
+		} else if !self.follow && !seen_data {
+			// A writer that connects and leaves without writing only
+			// raises EPOLLHUP; a watcher just reopens, a one-shot listener
+			// reports it.
+			return Err(PipeError::WriterClosed);
+		} else {
+			self.reopen(tag)?;
+		}
+
+		Ok(())
+	}
+
+	/// Takes the next connection on a `Backend::SeqPacket` listener, which
+	/// stays out of the epoll set until that connection is closed again.
+	fn accept(&mut self, tag: usize) -> Result<(), PipeError> {
+		let pipe = &mut self.pipes[tag];
+		let listener = match &pipe.listener {
+			Some(listener) => listener,
+			None => return Ok(()),
+		};
+
+		let conn = match seqpacket::accept(listener) {
+			Ok(conn) => conn,
+			Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+			Err(e) => {
+				self.deliver_err(PipeError::OpenFailed(e));
+				return Ok(());
+			}
+		};
+
+		let epoll_fd = self.epoll_fd;
+		let mut event = epoll_event {
+			events: self.opts.events.unwrap_or(EPOLLIN as u32),
+			u64: tag as u64,
+		};
+		check_errno!(epoll_ctl(
+			epoll_fd,
+			EPOLL_CTL_DEL,
+			listener.as_raw_fd(),
+			std::ptr::null_mut()
+		))
+		.and_then(|_| {
+			check_errno!(epoll_ctl(
+				epoll_fd,
+				EPOLL_CTL_ADD,
+				conn.as_raw_fd(),
+				&mut event
+			))
+		})
+		.map_err(PipeError::EpollFailed)?;
+		pipe.file = Some(conn);
+		Ok(())
+	}
+
+	/// Zeroes a frame that's being dropped with `ListenOptions::secret`.
+	fn discard(&self, frame: &mut Vec<u8>) {
+		if self.opts.secret {
+			secret::wipe(frame);
+		}
+	}
+
+	fn deliver(&mut self, tag: usize, mut frame: Vec<u8>) {
+		if self.opts.secret {
+			// Whatever truncating or taking off a prefix left behind.
+			secret::zero_spare(&mut frame);
+		}
+		if self.to_skip > 0 {
+			self.to_skip -= 1;
+			return self.discard(&mut frame);
+		}
+
+		let len = frame.len();
+		let expected = match frame.len().checked_sub(4) {
+			_ if !self.opts.verify_checksum => None,
+			Some(end) => {
+				let trailer = frame.split_off(end);
+				Some(u32::from_be_bytes([
+					trailer[0], trailer[1], trailer[2], trailer[3],
+				]))
+			}
+			None => {
+				return self.deliver_err(PipeError::ReadFailed(io::Error::new(
+					io::ErrorKind::InvalidData,
+					"message too short for a checksum",
+				)))
+			}
+		};
+		let checksum = crc32(&frame);
+		if let Some(expected) = expected.filter(|&e| e != checksum) {
+			self.discard(&mut frame);
+			return self.deliver_err(PipeError::ChecksumMismatch {
+				expected,
+				actual: checksum,
+			});
+		}
+
+		if self.opts.secret && std::str::from_utf8(&frame).is_err() {
+			// Decoding would drop the bytes without zeroing them.
+			self.discard(&mut frame);
+			return self.deliver_err(PipeError::ReadFailed(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"secret isn't valid UTF-8",
+			)));
+		}
+		let mut content = match self.opts.encoding.decode(frame) {
+			Ok(content) => content,
+			Err(e) => return self.deliver_err(PipeError::ReadFailed(e)),
+		};
+		if let (Backend::Fifo, Framing::Session) =
+			(self.opts.backend, self.opts.framing)
+		{
+			trim_in_place(&mut content);
+		}
+		if self.opts.secret {
+			// Dropping a byte order mark or trimming shifts the rest down.
+			secret::zero_spare(unsafe { content.as_mut_vec() });
+		}
+
+		self.push_message(tag, content, Some(checksum), len);
+	}
+
+	/// Hands a message of `len` bytes read from pipe `tag` to the consumers.
+	fn push_message(
+		&mut self,
+		tag: usize,
+		content: String,
+		checksum: Option<u32>,
+		len: usize,
+	) {
+		let mut state = self.shared.lock();
+		state.messages += 1;
+		state.bytes += len;
+
+		let pipe = &mut state.pipes[tag];
+		pipe.messages += 1;
+		pipe.bytes += len;
+		pipe.last_message = Some(SystemTime::now());
+		let reached_max = self
+			.opts
+			.max_messages
+			.is_some_and(|max| state.messages >= max);
+
+		state.push(Ok(Message {
+			source: tag,
+			content,
+			len,
+			checksum,
+			events: self.pipes[tag].events,
+		}));
+
+		self.next = (tag + 1) % self.pipes.len();
+		self.done = !self.follow || reached_max;
+	}
+
+	fn deliver_err(&mut self, err: PipeError) {
+		let mut state = self.shared.lock();
+		state.log_error(err.to_string());
+		state.push(Err(err));
+		self.done = !self.follow;
+	}
+
+	/// Records a non-fatal problem with pipe `tag` in the error log.
+	fn log_error(&self, tag: usize, what: impl fmt::Display) {
+		let message = format!("{}: {}", self.pipes[tag].path.display(), what);
+		self.shared.lock().log_error(message);
+	}
+
+	fn log_oversize(&self, tag: usize, max: usize) {
+		let what = match self.opts.on_oversize {
+			Oversize::Truncate => "truncated",
+			_ => "skipped",
+		};
+		self.log_error(
+			tag,
+			format_args!("{} a message over {} bytes", what, max),
+		);
+	}
+
+	/// `EPOLLONESHOT` disarms a pipe after every event, so it has to be
+	/// armed again for as long as the pipe stays open.
+	fn rearm(&mut self, tag: usize) -> Result<(), PipeError> {
+		let events = self.opts.events.unwrap_or(EPOLLIN as u32);
+		if events & EPOLLONESHOT as u32 == 0 {
+			return Ok(());
+		}
+
+		if let Some(file) = &self.pipes[tag].file {
+			let mut event = epoll_event {
+				events,
+				u64: tag as u64,
+			};
+			check_errno!(epoll_ctl(
+				self.epoll_fd,
+				EPOLL_CTL_MOD,
+				file.as_raw_fd(),
+				&mut event
+			))
+			.map_err(PipeError::EpollFailed)?;
+		}
+		Ok(())
+	}
+
+	/// Every writer has hung up; a fresh descriptor waits for the next one
+	/// instead of spinning on EPOLLHUP. Dropping the old one also removes it
+	/// from the epoll set.
+	fn reopen(&mut self, tag: usize) -> Result<(), PipeError> {
+		let pipe = &mut self.pipes[tag];
+		pipe.file = None;
+		if self.opts.secret {
+			secret::wipe(&mut pipe.buf);
+		}
+		pipe.buf.clear();
+		pipe.seen_data = false;
+		pipe.discarding = false;
+
+		if let Some(listener) = &pipe.listener {
+			let mut event = epoll_event {
+				events: EPOLLIN as u32,
+				u64: LISTENER | tag as u64,
+			};
+			check_errno!(epoll_ctl(
+				self.epoll_fd,
+				EPOLL_CTL_ADD,
+				listener.as_raw_fd(),
+				&mut event
+			))
+			.map_err(PipeError::EpollFailed)?;
+			return Ok(());
+		}
+
+		match open_registered(self.epoll_fd, &pipe.path, tag, self.opts.events)
+		{
+			Ok(file) => pipe.file = Some(file),
+			Err(e) => {
+				self.shared.lock().log_error(e.to_string());
+				self.reopens.push(Reopen {
+					tag,
+					at: Instant::now() + INITIAL_BACKOFF,
+					backoff: INITIAL_BACKOFF,
+				})
+			}
+		}
+		Ok(())
+	}
+
+	fn retry_reopens(&mut self, now: Instant) {
+		for reopen in self.reopens.iter_mut().filter(|r| r.at <= now) {
+			let pipe = &mut self.pipes[reopen.tag];
+
+			match open_registered(
+				self.epoll_fd,
+				&pipe.path,
+				reopen.tag,
+				self.opts.events,
+			) {
+				Ok(file) => pipe.file = Some(file),
+				Err(e) => {
+					self.shared.lock().log_error(e.to_string());
+					reopen.backoff = cmp::min(reopen.backoff * 2, MAX_BACKOFF);
+					reopen.at = now + reopen.backoff;
+				}
+			}
+		}
+
+		let pipes = &self.pipes;
+		self.reopens.retain(|r| pipes[r.tag].file.is_none());
+	}
+}
+
+/// `str::trim()` without making a copy.
+fn trim_in_place(s: &mut String) {
+	s.truncate(s.trim_end().len());
+	let start = s.len() - s.trim_start().len();
+	s.drain(..start);
+}
+
+/// Cuts `frame` down to at most `max` bytes without splitting a character.
+fn truncate(mut frame: Vec<u8>, max: usize, encoding: Encoding) -> Vec<u8> {
+	let end = encoding.truncation_point(&frame, max);
+	frame.truncate(end);
+	frame
+}
+
+/// Converts a deadline into an `epoll_wait` timeout, rounding up so the wait
+/// never ends just short of it.
+fn epoll_timeout(deadline: Option<Instant>) -> c_int {
+	deadline.map_or(-1, |at| {
+		let wait = at.saturating_duration_since(Instant::now());
+		let ms = wait.as_micros().div_ceil(1000);
+		cmp::min(ms, c_int::MAX as u128) as c_int
+	})
+}
+
+/// Pops the next item queued for subscriber `id`. Once the epoll thread has
+/// exited and the queue is drained this keeps resolving to
+/// `PipeError::Cancelled`.
+pub(crate) fn poll_message(
+	shared: &Shared,
+	id: usize,
+	cx: &mut Context<'_>,
+) -> Poll<Result<Message, PipeError>> {
+	let mut state = shared.lock();
+	let closed = state.closed;
+	let sub = match state.subscribers.iter_mut().find(|s| s.id == id) {
+		Some(sub) => sub,
+		None => return Poll::Ready(Err(PipeError::Cancelled)),
+	};
+	sub.polled = true;
+
+	if matches!(sub.bound, Some((_, SlowSubscriber::Error))) && sub.missed > 0 {
+		Poll::Ready(Err(PipeError::Missed(mem::take(&mut sub.missed))))
+	} else if let Some(item) = sub.queue.pop_front() {
+		Poll::Ready(item)
+	} else if closed {
+		Poll::Ready(Err(PipeError::Cancelled))
+	} else {
+		// Pushing takes the waker, so still having one means nothing woke
+		// this consumer since it last returned `Pending`.
+		if sub.waker.is_some() {
+			shared.spurious_wakeups.fetch_add(1, Ordering::Relaxed);
+		}
+		shared.pending_polls.fetch_add(1, Ordering::Relaxed);
+
+		sub.waker = Some(cx.waker().clone());
+		Poll::Pending
+	}
+}
+
+pub(crate) fn poll_chunk(
+	shared: &Shared,
+	id: usize,
+	cx: &mut Context<'_>,
+) -> Poll<Option<Chunk>> {
+	let mut state = shared.lock();
+	let closed = state.closed;
+	let sub = match state.chunk_subscribers.iter_mut().find(|s| s.id == id) {
+		Some(sub) => sub,
+		None => return Poll::Ready(None),
+	};
+
+	match sub.queue.pop_front() {
+		Some(chunk) => Poll::Ready(Some(chunk)),
+		None if closed => Poll::Ready(None),
+		None => {
+			sub.waker = Some(cx.waker().clone());
+			Poll::Pending
+		}
+	}
+}
+
+/// Opens the pipe at `path` for reading without waiting for a writer, so one
+/// idle pipe can't hold up the others, and adds it to `epoll_fd` under `tag`.
+/// A non-blocking read-only open of a FIFO succeeds even with no writer
+/// (only write-only opens fail with `ENXIO`); waiting for one is left to
+/// epoll, which is what makes `ListenOptions::open_timeout` possible. The
+/// descriptor stays non-blocking so reads never hold up the other pipes.
+fn open_registered(
+	epoll_fd: c_int,
+	path: &Path,
+	tag: usize,
+	events: Option<u32>,
+) -> Result<File, PipeError> {
+	let file = OpenOptions::new()
+		.read(true)
+		.custom_flags(O_NONBLOCK)
+		.open(path)
+		.map_err(|e| {
+			PipeError::OpenFailed(io::Error::new(
+				e.kind(),
+				format!("{}: {}", path.display(), e),
+			))
+		})?;
+
+	let is_fifo = file
+		.metadata()
+		.map_err(PipeError::OpenFailed)?
+		.file_type()
+		.is_fifo();
+	if !is_fifo {
+		return Err(PipeError::NotAFifo);
+	}
+
+	let mut event = epoll_event {
+		events: events.unwrap_or(EPOLLIN as u32),
+		u64: tag as u64,
+	};
+
+	check_errno!(epoll_ctl(
+		epoll_fd,
+		EPOLL_CTL_ADD,
+		file.as_raw_fd(),
+		&mut event
+	))
+	.map_err(PipeError::EpollFailed)?;
+
+	Ok(file)
+}
+
+/// Binds a `Backend::SeqPacket` listener at `path` and adds it to
+/// `epoll_fd`.
+fn listen_registered(
+	epoll_fd: c_int,
+	path: &Path,
+	tag: usize,
+) -> Result<File, PipeError> {
+	let listener = seqpacket::bind_listener(path).map_err(|e| {
+		PipeError::OpenFailed(io::Error::new(
+			e.kind(),
+			format!("{}: {}", path.display(), e),
+		))
+	})?;
+
+	let mut event = epoll_event {
+		events: EPOLLIN as u32,
+		u64: LISTENER | tag as u64,
+	};
+	check_errno!(epoll_ctl(
+		epoll_fd,
+		EPOLL_CTL_ADD,
+		listener.as_raw_fd(),
+		&mut event
+	))
+	.map_err(PipeError::EpollFailed)?;
+
+	Ok(listener)
+}
+
+/// `epoll_create1(2)`, or on kernels and sandboxes which don't allow it
+/// (older than 2.6.27, or seccomp filters written before it) the original
+/// `epoll_create(2)` with close-on-exec set separately.
+fn create_epoll() -> io::Result<c_int> {
+	let e = match check_errno!(epoll_create1(O_CLOEXEC)) {
+		Ok(fd) => return Ok(fd),
+		Err(e) => e,
+	};
+	match e.raw_os_error() {
+		Some(libc::ENOSYS) | Some(libc::EPERM) | Some(libc::EINVAL) => {}
+		_ => return Err(e),
+	}
+
+	// The size is ignored, but has to be positive.
+	let fd = check_errno!(epoll_create(1))?;
+	if let Err(e) = check_errno!(libc::fcntl(fd, F_SETFD, FD_CLOEXEC)) {
+		unsafe { libc::close(fd) };
+		return Err(e);
+	}
+	Ok(fd)
+}
+
+fn available_bytes(file: &File) -> io::Result<usize> {
+	let mut n: c_int = 0;
+	check_errno!(ioctl(file.as_raw_fd(), FIONREAD, &mut n))?;
+	Ok(n as usize)
+}
+
+/// CPUs this process may run on, which are the valid `ListenOptions::cpu`
+/// values. Narrower than the online CPUs under `taskset` or a cpuset.
+pub fn allowed_cpus() -> io::Result<Vec<usize>> {
+	let mut set = unsafe { mem::zeroed::<cpu_set_t>() };
+	check_errno!(sched_getaffinity(0, mem::size_of::<cpu_set_t>(), &mut set))?;
+
+	Ok((0..CPU_SETSIZE as usize)
+		.filter(|&cpu| unsafe { CPU_ISSET(cpu, &set) })
+		.collect())
+}
+
+/// Restricts the calling thread to `cpu`, which must already have been
+/// checked against `allowed_cpus()`.
+fn pin_to_cpu(cpu: usize) -> io::Result<()> {
+	let mut set = unsafe { mem::zeroed::<cpu_set_t>() };
+	unsafe { CPU_SET(cpu, &mut set) };
+
+	check_errno!(sched_setaffinity(0, mem::size_of::<cpu_set_t>(), &set))?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::task::Wake;
+
+	struct Flag(AtomicBool);
+
+	impl Wake for Flag {
+		fn wake(self: Arc<Self>) {
+			self.0.store(true, Ordering::SeqCst);
+		}
+	}
+
+	/// A watcher's state without the epoll thread, with just the primary
+	/// subscribed.
+	fn shared() -> Shared {
+		let shared = Shared {
+			state: Mutex::new(PipeWriteListenState {
+				subscribers: Vec::new(),
+				chunk_subscribers: Vec::new(),
+				next_id: PRIMARY,
+				replay: VecDeque::new(),
+				replay_len: 0,
+				latest_only: false,
+				secret: false,
+				closed: false,
+				messages: 0,
+				bytes: 0,
+				pipes: Vec::new(),
+				errors: VecDeque::new(),
+				error_log_len: ERROR_LOG_LEN,
+			}),
+			pending_polls: AtomicU64::new(0),
+			spurious_wakeups: AtomicU64::new(0),
+			lock_acquisitions: AtomicU64::new(0),
+			lock_wait_ns: AtomicU64::new(0),
+			stopping: AtomicBool::new(false),
+			paused: AtomicBool::new(false),
+			chunk_subscribers: AtomicUsize::new(0),
+			writers: Vec::new(),
+			path_changes: Mutex::new(Some(Vec::new())),
+			wake_fd: -1,
+			thread: Mutex::new(None),
+		};
+		shared.lock().subscribe(None);
+		shared
+	}
+
+	fn msg(source: usize, content: &str) -> Result<Message, PipeError> {
+		Ok(Message {
+			source,
+			content: content.to_string(),
+			len: content.len(),
+			checksum: None,
+			events: 0,
+		})
+	}
+
+	fn queued(shared: &Shared, id: usize) -> Vec<String> {
+		let state = shared.lock();
+		let sub = state.subscribers.iter().find(|s| s.id == id).unwrap();
+		let contents = sub.queue.iter().map(|item| match item {
+			Ok(msg) => msg.content.clone(),
+			Err(e) => e.to_string(),
+		});
+		contents.collect()
+	}
+
+	fn poll(shared: &Shared, id: usize) -> Poll<Result<Message, PipeError>> {
+		let waker = Waker::from(Arc::new(Flag(AtomicBool::new(false))));
+		poll_message(shared, id, &mut Context::from_waker(&waker))
+	}
+
+	fn next(shared: &Shared, id: usize) -> String {
+		match poll(shared, id) {
+			Poll::Ready(Ok(msg)) => msg.content,
+			other => panic!("expected a message, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn sole_primary_queues_everything() {
+		let shared = shared();
+		shared.lock().push(msg(0, "a"));
+		shared.lock().push(Err(PipeError::Missed(1)));
+		shared.lock().push(msg(1, "b"));
+
+		assert_eq!(next(&shared, PRIMARY), "a");
+		match poll(&shared, PRIMARY) {
+			Poll::Ready(Err(PipeError::Missed(1))) => {}
+			other => panic!("expected the error, got {:?}", other),
+		}
+		assert_eq!(next(&shared, PRIMARY), "b");
+		assert!(poll(&shared, PRIMARY).is_pending());
+	}
+
+	#[test]
+	fn errors_only_reach_the_primary() {
+		let shared = shared();
+		let _ = poll(&shared, PRIMARY);
+		let id = shared.lock().subscribe(None);
+		shared.lock().push(Err(PipeError::Missed(1)));
+		shared.lock().push(msg(0, "a"));
+
+		assert_eq!(queued(&shared, id), ["a"]);
+		assert_eq!(queued(&shared, PRIMARY).len(), 2);
+	}
+
+	#[test]
+	fn unpolled_primary_stops_queueing_once_subscribed() {
+		let shared = shared();
+		shared.lock().push(msg(0, "a"));
+		let id = shared.lock().subscribe(None);
+		assert!(queued(&shared, PRIMARY).is_empty());
+
+		shared.lock().push(msg(0, "b"));
+		assert!(queued(&shared, PRIMARY).is_empty());
+		assert_eq!(queued(&shared, id), ["b"]);
+
+		assert!(poll(&shared, PRIMARY).is_pending());
+		shared.lock().push(msg(0, "c"));
+		assert_eq!(next(&shared, PRIMARY), "c");
+	}
+
+	#[test]
+	fn polled_primary_keeps_its_queue() {
+		let shared = shared();
+		assert!(poll(&shared, PRIMARY).is_pending());
+		shared.lock().push(msg(0, "a"));
+		shared.lock().subscribe(None);
+
+		assert_eq!(next(&shared, PRIMARY), "a");
+	}
+
+	#[test]
+	fn bounded_subscriber_drops_oldest() {
+		let shared = shared();
+		let id = shared
+			.lock()
+			.subscribe(Some((2, SlowSubscriber::DropOldest)));
+		for content in ["a", "b", "c", "d"].iter() {
+			shared.lock().push(msg(0, content));
+		}
+
+		assert_eq!(queued(&shared, id), ["c", "d"]);
+		assert_eq!(next(&shared, id), "c");
+		assert_eq!(next(&shared, id), "d");
+		assert!(poll(&shared, id).is_pending());
+	}
+
+	#[test]
+	fn bounded_subscriber_reports_what_it_missed() {
+		let shared = shared();
+		let id = shared.lock().subscribe(Some((2, SlowSubscriber::Error)));
+		for content in ["a", "b", "c", "d"].iter() {
+			shared.lock().push(msg(0, content));
+		}
+
+		match poll(&shared, id) {
+			Poll::Ready(Err(PipeError::Missed(2))) => {}
+			other => panic!("expected Missed(2), got {:?}", other),
+		}
+		assert_eq!(next(&shared, id), "c");
+		shared.lock().push(msg(0, "e"));
+		assert_eq!(next(&shared, id), "d");
+		assert_eq!(next(&shared, id), "e");
+	}
+
+	#[test]
+	fn zero_capacity_holds_one() {
+		let shared = shared();
+		let id = shared
+			.lock()
+			.subscribe(Some((0, SlowSubscriber::DropOldest)));
+		shared.lock().push(msg(0, "a"));
+		shared.lock().push(msg(0, "b"));
+
+		assert_eq!(queued(&shared, id), ["b"]);
+	}
+
+	#[test]
+	fn push_wakes_subscribers() {
+		let shared = shared();
+		let id = shared.lock().subscribe(None);
+		let flag = Arc::new(Flag(AtomicBool::new(false)));
+		let waker = Waker::from(flag.clone());
+		let mut cx = Context::from_waker(&waker);
+		assert!(poll_message(&shared, id, &mut cx).is_pending());
+
+		shared.lock().push(msg(0, "a"));
+		assert!(flag.0.load(Ordering::SeqCst));
+	}
+
+	#[test]
+	fn replay_primes_new_subscribers() {
+		let shared = shared();
+		shared.lock().set_replay_len(3);
+		for content in ["a", "b", "c", "d"].iter() {
+			shared.lock().push(msg(0, content));
+		}
+
+		let id = shared.lock().subscribe(None);
+		assert_eq!(queued(&shared, id), ["b", "c", "d"]);
+		let bounded = shared
+			.lock()
+			.subscribe(Some((2, SlowSubscriber::DropOldest)));
+		assert_eq!(queued(&shared, bounded), ["c", "d"]);
+	}
+
+	#[test]
+	fn shrinking_replay_keeps_the_newest() {
+		let shared = shared();
+		shared.lock().set_replay_len(3);
+		for content in ["a", "b", "c"].iter() {
+			shared.lock().push(msg(0, content));
+		}
+		shared.lock().set_replay_len(1);
+
+		let id = shared.lock().subscribe(None);
+		assert_eq!(queued(&shared, id), ["c"]);
+	}
+
+	#[test]
+	fn latest_only_keeps_one_per_pipe() {
+		let shared = shared();
+		shared.lock().latest_only = true;
+		for (source, content) in [(0, "a"), (1, "b"), (0, "c")].iter() {
+			shared.lock().push(msg(*source, content));
+		}
+
+		assert_eq!(queued(&shared, PRIMARY), ["b", "c"]);
+	}
+
+	#[test]
+	fn unsubscribe_forgets_the_queue() {
+		let shared = shared();
+		let _ = poll(&shared, PRIMARY);
+		let id = shared.lock().subscribe(None);
+		shared.lock().push(msg(0, "a"));
+		shared.lock().unsubscribe(id);
+
+		assert!(matches!(
+			poll(&shared, id),
+			Poll::Ready(Err(PipeError::Cancelled))
+		));
+		assert_eq!(next(&shared, PRIMARY), "a");
+	}
+
+	#[test]
+	fn closing_drains_then_cancels() {
+		let shared = shared();
+		shared.lock().push(msg(0, "a"));
+		shared.lock().close();
+
+		assert_eq!(next(&shared, PRIMARY), "a");
+		assert!(matches!(
+			poll(&shared, PRIMARY),
+			Poll::Ready(Err(PipeError::Cancelled))
+		));
+	}
+
+	#[test]
+	fn trims_both_ends() {
+		let mut s = String::from(" \t hello world\r\n");
+		trim_in_place(&mut s);
+		assert_eq!(s, "hello world");
+
+		let mut blank = String::from(" \n ");
+		trim_in_place(&mut blank);
+		assert_eq!(blank, "");
+	}
+
+	#[test]
+	fn epoll_timeout_rounds_up() {
+		let now = Instant::now();
+		assert_eq!(epoll_timeout(None), -1);
+		assert_eq!(epoll_timeout(Some(now)), 0);
+		let soon = now + Duration::from_micros(1500);
+		assert_eq!(epoll_timeout(Some(soon)), 2);
+		let far = now + Duration::from_secs(100 * 24 * 60 * 60);
+		assert_eq!(epoll_timeout(Some(far)), c_int::MAX);
+	}
+}
@@ -0,0 +1,121 @@
+/*
+ * pipe-poll
+ * Copyright (C) 2021 Safin Singh
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::{ListenOptions, PipeError, PipeWriteListen};
+use std::{
+	fs::OpenOptions,
+	future::Future,
+	io::Write,
+	os::unix::fs::FileTypeExt,
+	path::Path,
+	pin::Pin,
+	sync::{Arc, Mutex},
+	task::{Context, Poll, Waker},
+	thread,
+};
+
+/// Writes `payload` to the pipe at `path` as one write session, waiting for
+/// a reader to open the other end first. The pipe is closed by the time this
+/// returns, so the reader sees the session end before anything written
+/// afterwards.
+pub async fn write_to_pipe<P: AsRef<Path>>(
+	path: P,
+	payload: &str,
+) -> Result<(), PipeError> {
+	let path = path.as_ref().to_path_buf();
+	let payload = payload.to_string();
+
+	// async-std's `File` closes the descriptor whenever its last background
+	// operation lets go of it, so a quick reopen by the reader could still
+	// find this writer attached and read an empty session. A plain blocking
+	// write on a thread of its own closes it before reporting back.
+	let written = Written::default();
+	let done = written.clone();
+	thread::spawn(move || {
+		let result = write_blocking(&path, &payload);
+		let mut done = done.0.lock().unwrap();
+		done.result = Some(result);
+		if let Some(waker) = done.waker.take() {
+			waker.wake()
+		}
+	});
+
+	written.await
+}
+
+/// Resolves once the writing thread has filled in the result.
+#[derive(Clone, Default)]
+struct Written(Arc<Mutex<Outcome>>);
+
+#[derive(Default)]
+struct Outcome {
+	result: Option<Result<(), PipeError>>,
+	waker: Option<Waker>,
+}
+
+impl Future for Written {
+	type Output = Result<(), PipeError>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let mut written = self.0.lock().unwrap();
+		match written.result.take() {
+			Some(result) => Poll::Ready(result),
+			None => {
+				written.waker = Some(cx.waker().clone());
+				Poll::Pending
+			}
+		}
+	}
+}
+
+fn write_blocking(path: &Path, payload: &str) -> Result<(), PipeError> {
+	let mut file = OpenOptions::new()
+		.write(true)
+		.open(path)
+		.map_err(PipeError::OpenFailed)?;
+
+	let is_fifo = file
+		.metadata()
+		.map_err(PipeError::OpenFailed)?
+		.file_type()
+		.is_fifo();
+	if !is_fifo {
+		return Err(PipeError::NotAFifo);
+	}
+
+	file.write_all(payload.as_bytes())
+		.and_then(|_| file.flush())
+		.map_err(PipeError::WriteFailed)
+}
+
+/// Writes `payload` to `req` and resolves with the reply read from `resp`.
+/// The listener on `resp` is set up before the request goes out so a fast
+/// responder can't reply before anyone is reading.
+pub async fn request<P, Q>(
+	req: P,
+	resp: Q,
+	payload: &str,
+) -> Result<String, PipeError>
+where
+	P: AsRef<Path>,
+	Q: AsRef<Path>,
+{
+	let reply = PipeWriteListen::new(&[resp], &ListenOptions::default())?;
+	write_to_pipe(req, payload).await?;
+	reply.await
+}
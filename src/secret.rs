@@ -0,0 +1,117 @@
+/*
+ * pipe-poll
+ * Copyright (C) 2021 Safin Singh
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{
+	fmt,
+	ops::Deref,
+	ptr,
+	sync::atomic::{self, Ordering},
+};
+
+/// Overwrites `bytes` with zeroes in a way the compiler can't optimise out
+/// as a dead store.
+pub(crate) fn zero(bytes: &mut [u8]) {
+	for byte in bytes.iter_mut() {
+		unsafe { ptr::write_volatile(byte, 0) };
+	}
+	atomic::compiler_fence(Ordering::SeqCst);
+}
+
+/// Zeroes the unused capacity of `buf`, where draining or truncating it
+/// leaves stale bytes behind.
+pub(crate) fn zero_spare(buf: &mut Vec<u8>) {
+	let spare = buf.capacity() - buf.len();
+	unsafe {
+		let start = buf.as_mut_ptr().add(buf.len());
+		zero(std::slice::from_raw_parts_mut(start, spare));
+	}
+}
+
+/// Zeroes all of `buf`, including its unused capacity, and empties it.
+pub(crate) fn wipe(buf: &mut Vec<u8>) {
+	zero(buf);
+	buf.clear();
+	zero_spare(buf);
+}
+
+/// Appends `bytes` to `buf`, growing it by hand when it's full so the old
+/// allocation is zeroed before it's freed instead of being left to the
+/// allocator by `Vec`'s own reallocation.
+pub(crate) fn extend(buf: &mut Vec<u8>, bytes: &[u8]) {
+	if buf.capacity() - buf.len() < bytes.len() {
+		let needed = buf.len() + bytes.len();
+		let mut grown = Vec::with_capacity(needed.max(buf.capacity() * 2));
+		grown.extend_from_slice(buf);
+		wipe(buf);
+		*buf = grown;
+	}
+	buf.extend_from_slice(bytes);
+}
+
+/// `Vec::resize(len, 0)` for growing `buf`, zeroing the old allocation
+/// first like `extend()` does.
+pub(crate) fn grow(buf: &mut Vec<u8>, len: usize) {
+	let mut grown = vec![0; len];
+	grown[..buf.len()].copy_from_slice(buf);
+	wipe(buf);
+	*buf = grown;
+}
+
+/// `wipe()` for text.
+pub(crate) fn wipe_str(s: &mut String) {
+	// Only ever emptied, so it stays valid UTF-8.
+	wipe(unsafe { s.as_mut_vec() });
+}
+
+/// The content of a message read with `ListenOptions::secret`, which is
+/// zeroed, spare capacity and all, once this is dropped. It's never
+/// printed by `Debug`.
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+	pub fn as_str(&self) -> &str {
+		// Only ever made from a `String`.
+		unsafe { std::str::from_utf8_unchecked(&self.0) }
+	}
+}
+
+impl From<String> for Secret {
+	fn from(s: String) -> Self {
+		Secret(s.into_bytes())
+	}
+}
+
+impl Deref for Secret {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		&self.0
+	}
+}
+
+impl fmt::Debug for Secret {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Secret({} bytes)", self.0.len())
+	}
+}
+
+impl Drop for Secret {
+	fn drop(&mut self) {
+		wipe(&mut self.0);
+	}
+}
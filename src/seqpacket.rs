@@ -0,0 +1,112 @@
+/*
+ * pipe-poll
+ * Copyright (C) 2021 Safin Singh
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Unix `SOCK_SEQPACKET` sockets, which keep message boundaries intact.
+
+use crate::secret;
+use libc::{
+	accept4, bind, listen, recv, sa_family_t, sockaddr, sockaddr_un, socket,
+	socklen_t, AF_UNIX, MSG_PEEK, MSG_TRUNC, SOCK_CLOEXEC, SOCK_NONBLOCK,
+	SOCK_SEQPACKET,
+};
+use std::{
+	fs::{self, File},
+	io, mem,
+	os::unix::{
+		ffi::OsStrExt,
+		fs::FileTypeExt,
+		io::{AsRawFd, FromRawFd},
+	},
+	path::Path,
+};
+
+/// Binds a non-blocking listening socket to `path`, replacing a stale socket
+/// left behind there by an earlier run.
+pub(crate) fn bind_listener(path: &Path) -> io::Result<File> {
+	let mut addr = unsafe { mem::zeroed::<sockaddr_un>() };
+	addr.sun_family = AF_UNIX as sa_family_t;
+
+	let bytes = path.as_os_str().as_bytes();
+	if bytes.len() >= addr.sun_path.len() {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			"socket path too long",
+		));
+	}
+	for (dst, src) in addr.sun_path.iter_mut().zip(bytes) {
+		*dst = *src as _;
+	}
+
+	if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+		fs::remove_file(path)?;
+	}
+
+	let fd = check_errno!(socket(
+		AF_UNIX,
+		SOCK_SEQPACKET | SOCK_NONBLOCK | SOCK_CLOEXEC,
+		0
+	))?;
+	let listener = unsafe { File::from_raw_fd(fd) };
+
+	check_errno!(bind(
+		fd,
+		&addr as *const sockaddr_un as *const sockaddr,
+		mem::size_of::<sockaddr_un>() as socklen_t,
+	))?;
+	check_errno!(listen(fd, 16))?;
+
+	Ok(listener)
+}
+
+/// Accepts a pending connection as a non-blocking socket.
+pub(crate) fn accept(listener: &File) -> io::Result<File> {
+	let fd = check_errno!(accept4(
+		listener.as_raw_fd(),
+		std::ptr::null_mut(),
+		std::ptr::null_mut(),
+		SOCK_NONBLOCK | SOCK_CLOEXEC,
+	))?;
+
+	Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Receives the next datagram whole, peeking at its length first and growing
+/// `buf` to fit, zeroing what the peek left in the old allocation if it's a
+/// `secret`. 0 means the peer has shut down, as empty datagrams can't be
+/// told apart from that.
+pub(crate) fn recv_datagram(
+	conn: &File,
+	buf: &mut Vec<u8>,
+	secret: bool,
+) -> io::Result<usize> {
+	let fd = conn.as_raw_fd();
+
+	let len = check_errno!(recv(
+		fd,
+		buf.as_mut_ptr() as _,
+		buf.len(),
+		MSG_PEEK | MSG_TRUNC
+	))? as usize;
+	if len > buf.len() && secret {
+		secret::grow(buf, len);
+	} else if len > buf.len() {
+		buf.resize(len, 0);
+	}
+
+	Ok(check_errno!(recv(fd, buf.as_mut_ptr() as _, buf.len(), 0))? as usize)
+}
@@ -0,0 +1,294 @@
+/*
+ * pipe-poll
+ * Copyright (C) 2021 Safin Singh
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::blocking::block_on;
+use crate::reader::{self, Shared};
+use crate::{
+	Chunk, ListenOptions, LoggedError, Message, PipeError, PipeStats,
+	SlowSubscriber, Stats,
+};
+#[cfg(feature = "runtime")]
+use async_std::stream::Stream;
+use std::{
+	future::{self, Future},
+	path::Path,
+	pin::Pin,
+	sync::Arc,
+	task::{Context, Poll},
+};
+
+/// A long-lived handle over one or more pipes which keeps yielding messages
+/// across writers. Whenever every writer has hung up the pipe is reopened,
+/// backing off while the path can't be opened (e.g. while it's being
+/// recreated), so callers never see the disconnect.
+pub struct PipeWatcher {
+	shared: Arc<Shared>,
+}
+
+/// An additional consumer of a `PipeWatcher` which receives its own copy of
+/// every message, starting with the watcher's replay buffer. Read errors
+/// only go to the watcher itself. As a `Stream` it ends once the watcher
+/// has stopped.
+pub struct Subscription {
+	shared: Arc<Shared>,
+	id: usize,
+}
+
+/// Raw input of a `PipeWatcher` as it's read, a `Stream` of `Chunk`s which
+/// ends once the watcher has stopped. Chunks bypass framing, decoding,
+/// `skip` and `max_bytes`, so a huge write session can be processed
+/// piecemeal instead of arriving as one message; a `Chunk::End` follows the
+/// last piece of each session.
+pub struct Chunks {
+	shared: Arc<Shared>,
+	id: usize,
+}
+
+impl Chunks {
+	/// Blocks until the next chunk, see `PipeWatcher::next_blocking()`.
+	pub fn next_blocking(&mut self) -> Option<Chunk> {
+		block_on(future::poll_fn(|cx| {
+			reader::poll_chunk(&self.shared, self.id, cx)
+		}))
+	}
+}
+
+#[cfg(feature = "runtime")]
+impl Stream for Chunks {
+	type Item = Chunk;
+
+	fn poll_next(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+	) -> Poll<Option<Self::Item>> {
+		reader::poll_chunk(&self.shared, self.id, cx)
+	}
+}
+
+impl Drop for Chunks {
+	fn drop(&mut self) {
+		self.shared.unsubscribe_chunks(self.id);
+	}
+}
+
+/// Resolves to the next message read by a `PipeWatcher` or `Subscription`.
+/// Read errors are delivered in order and watching carries on after them;
+/// once the watcher has stopped this resolves to `PipeError::Cancelled`.
+pub struct NextMessage<'a> {
+	shared: &'a Shared,
+	id: usize,
+}
+
+impl Future for NextMessage<'_> {
+	type Output = Result<Message, PipeError>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		reader::poll_message(self.shared, self.id, cx)
+	}
+}
+
+impl PipeWatcher {
+	/// Watches the single pipe at `path` with the default options.
+	pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, PipeError> {
+		Self::open_all(&[path], &ListenOptions::default())
+	}
+
+	/// Watches every pipe in `paths`. `Message::source` is the index of the
+	/// pipe a message came from.
+	pub fn open_all<P: AsRef<Path>>(
+		paths: &[P],
+		opts: &ListenOptions,
+	) -> Result<Self, PipeError> {
+		let shared = reader::spawn(paths, opts, true)?;
+		Ok(PipeWatcher { shared })
+	}
+
+	/// Keeps the last `len` messages around and replays them to every
+	/// subsequent `subscribe()` call.
+	pub fn with_replay(self, len: usize) -> Self {
+		self.shared.lock().set_replay_len(len);
+		self
+	}
+
+	/// Remembers the last `len` non-fatal errors, 32 by default, for
+	/// `recent_errors()`.
+	pub fn with_error_log(self, len: usize) -> Self {
+		self.shared.lock().set_error_log_len(len);
+		self
+	}
+
+	pub fn next(&self) -> NextMessage<'_> {
+		NextMessage {
+			shared: &self.shared,
+			id: reader::PRIMARY,
+		}
+	}
+
+	/// `next()` for callers without an async runtime, blocking the calling
+	/// thread.
+	pub fn next_blocking(&self) -> Result<Message, PipeError> {
+		block_on(self.next())
+	}
+
+	/// Adds a consumer of its own, see `Subscription`. Unless `next()` has
+	/// been called already, the watcher stops queueing messages for itself
+	/// from here on and drops what it had queued, so it doesn't keep an
+	/// unbounded backlog nobody reads; `next()` picks up from the point of
+	/// its first call.
+	pub fn subscribe(&self) -> Subscription {
+		let id = self.shared.lock().subscribe(None);
+		Subscription {
+			shared: self.shared.clone(),
+			id,
+		}
+	}
+
+	/// `subscribe()`, but with at most `capacity` messages queued, so a
+	/// subscriber that doesn't keep up can't hold on to an unbounded
+	/// backlog. `slow` says what happens once it's full.
+	pub fn subscribe_bounded(
+		&self,
+		capacity: usize,
+		slow: SlowSubscriber,
+	) -> Subscription {
+		let id = self.shared.lock().subscribe(Some((capacity, slow)));
+		Subscription {
+			shared: self.shared.clone(),
+			id,
+		}
+	}
+
+	/// Starts receiving raw chunks from here on, alongside the messages.
+	pub fn chunks(&self) -> Chunks {
+		Chunks {
+			shared: self.shared.clone(),
+			id: self.shared.subscribe_chunks(),
+		}
+	}
+
+	/// Counters describing how the handoff from the epoll thread to this
+	/// watcher's consumers has been behaving.
+	pub fn stats(&self) -> Stats {
+		self.shared.stats()
+	}
+
+	/// Stops watching and blocks until every pipe has been closed. Pending
+	/// `next()` calls on this watcher and its subscriptions resolve to
+	/// `PipeError::Cancelled` once they have drained their queues.
+	pub fn shutdown(&self) {
+		self.shared.shutdown();
+	}
+
+	/// Stops reading until `resume()`. Writes pile up in the pipes and
+	/// block producers once a pipe is full; nothing is lost. Messages that
+	/// were already read can still be taken.
+	pub fn pause(&self) {
+		self.shared.set_paused(true)
+	}
+
+	pub fn resume(&self) {
+		self.shared.set_paused(false)
+	}
+
+	pub fn is_paused(&self) -> bool {
+		self.shared.is_paused()
+	}
+
+	/// Watches `path` instead of pipe `source` from now on, e.g. after a
+	/// config reload, without disturbing the other pipes or anything
+	/// already queued. Nothing more is read from the old pipe, including a
+	/// partial frame; if `path` can't be opened the old pipe stays.
+	pub fn set_path(
+		&self,
+		source: usize,
+		path: impl AsRef<Path>,
+	) -> Result<(), PipeError> {
+		self.shared.set_path(source, path.as_ref().to_path_buf())
+	}
+
+	/// Whether any of the pipes had a writer connected as of the last read.
+	/// A writer that has connected but not written anything yet isn't
+	/// visible to epoll, so this only changes once data or a hangup arrives.
+	pub fn is_writer_connected(&self) -> bool {
+		self.shared.writer_connected()
+	}
+
+	/// Per pipe counters, in the order the pipes were given in.
+	pub fn pipe_stats(&self) -> Vec<PipeStats> {
+		self.shared.pipe_stats()
+	}
+
+	/// The most recent non-fatal errors, oldest first: read errors,
+	/// interrupted reads, oversized messages that were cut or dropped, pipes
+	/// that couldn't be reopened and anything passed to `log_error()`.
+	pub fn recent_errors(&self) -> Vec<LoggedError> {
+		self.shared.lock().recent_errors()
+	}
+
+	/// Adds an error of the caller's own, e.g. from writing out a message,
+	/// to `recent_errors()`.
+	pub fn log_error(&self, message: impl ToString) {
+		self.shared.lock().log_error(message.to_string());
+	}
+
+	/// Number of messages and bytes read so far.
+	pub fn totals(&self) -> (usize, usize) {
+		let state = self.shared.lock();
+		(state.messages, state.bytes)
+	}
+}
+
+/// Dropping the watcher stops the epoll thread and closes the pipes, like
+/// `shutdown()`. Its `Subscription`s and `Chunks` outlive it: they still
+/// hand out whatever they had queued, then resolve to
+/// `PipeError::Cancelled` (or end, as a `Stream`).
+impl Drop for PipeWatcher {
+	fn drop(&mut self) {
+		self.shared.shutdown();
+	}
+}
+
+impl Subscription {
+	pub fn next(&self) -> NextMessage<'_> {
+		NextMessage {
+			shared: &self.shared,
+			id: self.id,
+		}
+	}
+}
+
+#[cfg(feature = "runtime")]
+impl Stream for Subscription {
+	type Item = Result<Message, PipeError>;
+
+	fn poll_next(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+	) -> Poll<Option<Self::Item>> {
+		reader::poll_message(&self.shared, self.id, cx).map(|item| match item {
+			Err(PipeError::Cancelled) => None,
+			item => Some(item),
+		})
+	}
+}
+
+impl Drop for Subscription {
+	fn drop(&mut self) {
+		self.shared.lock().unsubscribe(self.id);
+	}
+}
@@ -0,0 +1,91 @@
+/*
+ * pipe-poll
+ * Copyright (C) 2021 Safin Singh
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Counting the writers a FIFO has. The kernel doesn't keep count, so this
+//! goes through every process's descriptors in `/proc`, which only sees the
+//! ones we're allowed to look at (all of them as root).
+
+use std::{fs, os::unix::fs::MetadataExt, path::Path, process};
+
+/// Number of times other processes have opened the FIFO at `path` for
+/// writing. Descriptors inherited from, or shared with, another process
+/// (e.g. a shell redirecting the output of `timeout sh -c ...`) count once.
+/// Anything unreadable counts as no writers.
+pub(crate) fn count(path: &Path) -> usize {
+	let fifo = match fs::metadata(path) {
+		Ok(meta) => (meta.dev(), meta.ino()),
+		Err(_) => return 0,
+	};
+	let own = process::id().to_string();
+	let procs = match fs::read_dir("/proc") {
+		Ok(procs) => procs,
+		Err(_) => return 0,
+	};
+
+	let mut writers: Vec<(i32, i32)> = Vec::new();
+	for entry in procs.flatten() {
+		let pid = entry.file_name();
+		let pid = match pid.to_str() {
+			Some(pid) if pid != own && pid.parse::<u32>().is_ok() => pid,
+			_ => continue,
+		};
+		let fds = match fs::read_dir(entry.path().join("fd")) {
+			Ok(fds) => fds,
+			Err(_) => continue,
+		};
+		for fd in fds.flatten() {
+			let same = fs::metadata(fd.path())
+				.is_ok_and(|meta| (meta.dev(), meta.ino()) == fifo);
+			let fd = fd.file_name().to_string_lossy().into_owned();
+			if !same || !writable(pid, &fd) {
+				continue;
+			}
+			let open = (pid.parse().unwrap(), fd.parse().unwrap_or(-1));
+			if !writers.iter().any(|&other| same_open(open, other)) {
+				writers.push(open);
+			}
+		}
+	}
+	writers.len()
+}
+
+/// Whether two `(pid, fd)` descriptors come from the same `open(2)`, with
+/// `kcmp(2)`. Treated as separate opens when that isn't allowed.
+fn same_open((pid1, fd1): (i32, i32), (pid2, fd2): (i32, i32)) -> bool {
+	const KCMP_FILE: libc::c_long = 0;
+	let res = unsafe {
+		libc::syscall(libc::SYS_kcmp, pid1, pid2, KCMP_FILE, fd1, fd2)
+	};
+	res == 0
+}
+
+/// Whether descriptor `fd` of process `pid` was opened with `O_WRONLY` or
+/// `O_RDWR`, going by the octal `flags:` line of its fdinfo.
+fn writable(pid: &str, fd: &str) -> bool {
+	let info = match fs::read_to_string(format!("/proc/{}/fdinfo/{}", pid, fd))
+	{
+		Ok(info) => info,
+		Err(_) => return false,
+	};
+	info.lines()
+		.find_map(|line| line.strip_prefix("flags:"))
+		.and_then(|flags| i32::from_str_radix(flags.trim(), 8).ok())
+		.is_some_and(|flags| {
+			matches!(flags & libc::O_ACCMODE, libc::O_WRONLY | libc::O_RDWR)
+		})
+}